@@ -0,0 +1,89 @@
+//! one block's worth of writes applied to a committed trie, key by key and
+//! with `apply_batch`. run with `cargo bench --bench batch`.
+//!
+//! both start from the same committed 100k-key state and end by computing
+//! the root. the batch visits each node shared by several keys once
+//! instead of once per key.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use merkle_trie_rs::db::MemoryDb;
+use merkle_trie_rs::node::keccak256;
+use merkle_trie_rs::trie::EthTrie;
+
+const BASE_KEYS: u32 = 100_000;
+
+fn key(i: u32) -> [u8; 32] {
+    keccak256(&i.to_be_bytes())
+}
+
+/// every third write updates an existing key, every fifth removes one, and
+/// the rest add new keys.
+fn writes(count: u32) -> Vec<([u8; 32], Option<Vec<u8>>)> {
+    (0..count)
+        .map(|i| match i % 15 {
+            0 | 5 | 10 => (key(i * 7 % BASE_KEYS), None),
+            n if n % 3 == 0 => (key(i * 13 % BASE_KEYS), Some(format!("updated-{}", i).into_bytes())),
+            _ => (key(BASE_KEYS + i), Some(format!("new-{}", i).into_bytes())),
+        })
+        .collect()
+}
+
+/// rounds per measurement; the median is reported, since single runs of
+/// a few milliseconds swing widely.
+const ROUNDS: usize = 7;
+
+/// the median time of `run` on a fresh trie opened at `root` each round,
+/// and the root it left behind.
+fn median(root: [u8; 32], db: &MemoryDb, mut run: impl FnMut(&mut EthTrie)) -> (Duration, [u8; 32]) {
+    let mut times = Vec::with_capacity(ROUNDS);
+    let mut result = root;
+    for _ in 0..ROUNDS {
+        let mut trie = EthTrie::from_root(root, db.clone());
+        let start = Instant::now();
+        run(&mut trie);
+        times.push(start.elapsed());
+        result = trie.root_hash();
+    }
+    times.sort();
+    (times[ROUNDS / 2], result)
+}
+
+fn main() {
+    let mut base = EthTrie::new();
+    for i in 0..BASE_KEYS {
+        base.insert(&key(i), &key(i)[..8]).unwrap();
+    }
+    let root = base.commit().unwrap();
+    let db: MemoryDb = base.into_db();
+
+    println!("{:>8} {:>16} {:>16} {:>9}", "writes", "sequential (ms)", "batch (ms)", "speedup");
+    for count in [1_000u32, 5_000, 20_000] {
+        let writes = writes(count);
+
+        let (sequential, expected) = median(root, &db, |trie| {
+            for (key, value) in &writes {
+                match value {
+                    Some(value) => trie.insert(key, value).unwrap(),
+                    None => {
+                        trie.remove(key).unwrap();
+                    }
+                }
+            }
+            black_box(trie.root_hash());
+        });
+        let (batch, batch_root) = median(root, &db, |trie| {
+            black_box(trie.apply_batch(writes.iter().map(|(key, value)| (key, value.as_ref()))).unwrap());
+        });
+        assert_eq!(batch_root, expected, "batch and sequential roots differ");
+
+        println!(
+            "{:>8} {:>16.1} {:>16.1} {:>8.2}x",
+            count,
+            sequential.as_secs_f64() * 1_000.0,
+            batch.as_secs_f64() * 1_000.0,
+            sequential.as_secs_f64() / batch.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,42 @@
+//! insert throughput for growing key counts. run with `cargo bench --bench insert`.
+//!
+//! inserting one key only rewrites the nodes on its path, so the time per
+//! key should stay roughly flat (logarithmic in the key count) as the trie
+//! grows rather than growing with it.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use merkle_trie_rs::node::keccak256;
+use merkle_trie_rs::trie::EthTrie;
+
+fn main() {
+    println!("{:>10} {:>12} {:>14} {:>14}", "keys", "total (ms)", "ns per key", "keys per sec");
+
+    let mut baseline = None;
+    for count in [10_000u32, 20_000, 40_000, 80_000, 160_000] {
+        let keys: Vec<[u8; 32]> = (0..count).map(|i| keccak256(&i.to_be_bytes())).collect();
+
+        let start = Instant::now();
+        let mut trie = EthTrie::new();
+        for key in &keys {
+            trie.insert(key, &key[..8]).unwrap();
+        }
+        black_box(trie.root_hash());
+        let elapsed = start.elapsed();
+
+        let per_key = elapsed.as_nanos() as f64 / count as f64;
+        println!(
+            "{:>10} {:>12.1} {:>14.0} {:>14.0}",
+            count,
+            elapsed.as_secs_f64() * 1_000.0,
+            per_key,
+            count as f64 / elapsed.as_secs_f64()
+        );
+
+        let baseline = *baseline.get_or_insert(per_key);
+        if per_key > baseline * 4.0 {
+            println!("warning: per-key cost grew {:.1}x over the smallest run", per_key / baseline);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! root hash of a freshly built 1M-key trie on pools of growing size. run
+//! with `cargo bench --features parallel --bench parallel`.
+//!
+//! the single-thread pool runs the same code without any parallelism and is
+//! the baseline. every run starts from identical unhashed nodes and must
+//! produce the same root.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use merkle_trie_rs::node::keccak256;
+use merkle_trie_rs::trie::EthTrie;
+
+const KEYS: u32 = 1_000_000;
+
+fn build() -> EthTrie {
+    let mut trie = EthTrie::new();
+    for i in 0..KEYS {
+        let key = keccak256(&i.to_be_bytes());
+        trie.insert(&key, &key[..8]).unwrap();
+    }
+    trie
+}
+
+fn main() {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut threads = vec![1];
+    while threads.last().unwrap() * 2 < cores {
+        threads.push(threads.last().unwrap() * 2);
+    }
+    if cores > 1 {
+        threads.push(cores);
+    }
+
+    println!("{:>8} {:>12} {:>9}", "threads", "hash (ms)", "speedup");
+    let mut baseline: Option<(Duration, [u8; 32])> = None;
+    for count in threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(count).build().unwrap();
+        let trie = build();
+
+        let (elapsed, root) = pool.install(move || {
+            let start = Instant::now();
+            let root = black_box(trie.root_hash());
+            (start.elapsed(), root)
+        });
+
+        let (base, expected) = *baseline.get_or_insert((elapsed, root));
+        assert_eq!(root, expected, "root differs with {} threads", count);
+        println!(
+            "{:>8} {:>12.1} {:>8.2}x",
+            count,
+            elapsed.as_secs_f64() * 1_000.0,
+            base.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
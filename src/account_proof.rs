@@ -0,0 +1,366 @@
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+
+use crate::db::NodeDb;
+use crate::error::{AccountProofError, TrieError};
+use crate::secure::SecureTrie;
+use crate::state::{slot_key, Account, Address, StateTrie, EMPTY_CODE_HASH};
+use crate::trie::EMPTY_ROOT;
+
+/// an `eth_getProof` (EIP-1186) response: an account with its proof under
+/// the state root, and storage slots with their proofs under the account's
+/// storage root. serializes to the json nodes return, with `0x`-prefixed
+/// hex data and quantities.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountProof {
+    #[serde(with = "data")]
+    pub address: Address,
+    #[serde(with = "data_list")]
+    pub account_proof: Vec<Vec<u8>>,
+    #[serde(with = "quantity")]
+    pub balance: U256,
+    #[serde(with = "data")]
+    pub code_hash: [u8; 32],
+    #[serde(with = "quantity")]
+    pub nonce: u64,
+    #[serde(with = "data")]
+    pub storage_hash: [u8; 32],
+    pub storage_proof: Vec<StorageProof>,
+}
+
+/// one storage slot of an `AccountProof`. slots that are not set have value
+/// zero and a proof of absence.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageProof {
+    #[serde(with = "slot")]
+    pub key: U256,
+    #[serde(with = "quantity")]
+    pub value: U256,
+    #[serde(with = "data_list")]
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl<D: NodeDb> StateTrie<D> {
+    /// the `eth_getProof` response for `address` and `slots` under
+    /// `root_hash()`. like the root, it does not include storage writes
+    /// pending until the next `commit`.
+    pub fn get_account_proof(&self, address: &Address, slots: &[U256]) -> Result<AccountProof, TrieError> {
+        let account = self.get_account(address)?.unwrap_or_default();
+        let storage = self.open_storage(address)?;
+
+        let storage_proof = slots
+            .iter()
+            .map(|&slot| {
+                let key = slot_key(slot);
+                let value = match storage.get(&key)? {
+                    Some(encoded) => rlp::decode(&encoded).map_err(|source| TrieError::InvalidStorage {
+                        address: *address,
+                        source,
+                    })?,
+                    None => U256::zero(),
+                };
+                Ok(StorageProof {
+                    key: slot,
+                    value,
+                    proof: storage.get_proof(&key)?,
+                })
+            })
+            .collect::<Result<_, TrieError>>()?;
+
+        Ok(AccountProof {
+            address: *address,
+            account_proof: self.get_proof(address)?,
+            balance: account.balance,
+            code_hash: account.code_hash,
+            nonce: account.nonce,
+            storage_hash: account.storage_root,
+            storage_proof,
+        })
+    }
+}
+
+/// checks an `eth_getProof` response against `state_root`: the account
+/// fields must be what the account proof shows, or empty if it proves the
+/// account absent, and each slot value must be what its storage proof shows.
+pub fn verify_account_proof(state_root: &[u8; 32], proof: &AccountProof) -> Result<(), AccountProofError> {
+    let claimed = Account {
+        nonce: proof.nonce,
+        balance: proof.balance,
+        storage_root: proof.storage_hash,
+        code_hash: proof.code_hash,
+    };
+
+    let storage_root = match SecureTrie::verify_proof(state_root, &proof.address, &proof.account_proof)? {
+        Some(encoded) => {
+            let account: Account = rlp::decode(&encoded).map_err(AccountProofError::InvalidAccount)?;
+            if account != claimed {
+                return Err(AccountProofError::AccountMismatch);
+            }
+            account.storage_root
+        }
+        None => {
+            // some nodes report zero hashes rather than the empty root and
+            // empty code hash for a missing account.
+            let empty = claimed.nonce == 0
+                && claimed.balance.is_zero()
+                && [EMPTY_ROOT, [0; 32]].contains(&claimed.storage_root)
+                && [EMPTY_CODE_HASH, [0; 32]].contains(&claimed.code_hash);
+            if !empty {
+                return Err(AccountProofError::AccountMismatch);
+            }
+            EMPTY_ROOT
+        }
+    };
+
+    for storage in &proof.storage_proof {
+        let slot = storage.key;
+        let value = match SecureTrie::verify_proof(&storage_root, &slot_key(slot), &storage.proof)? {
+            Some(encoded) => {
+                rlp::decode(&encoded).map_err(|source| AccountProofError::InvalidStorage { slot, source })?
+            }
+            None => U256::zero(),
+        };
+        if value != storage.value {
+            return Err(AccountProofError::StorageMismatch { slot });
+        }
+    }
+    Ok(())
+}
+
+fn parse_hex(text: &str) -> Result<&str, String> {
+    text.strip_prefix("0x")
+        .ok_or_else(|| format!("expected a 0x-prefixed hex string, got {:?}", text))
+}
+
+/// byte strings and fixed-size hashes as `0x`-prefixed hex.
+mod data {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, T: AsRef<[u8]>>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{}", hex::encode(bytes)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: TryFrom<Vec<u8>>>(deserializer: D) -> Result<T, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bytes = hex::decode(super::parse_hex(&text).map_err(D::Error::custom)?).map_err(D::Error::custom)?;
+        let len = bytes.len();
+        T::try_from(bytes).map_err(|_| D::Error::custom(format!("unexpected length {} for {:?}", len, text)))
+    }
+}
+
+/// proofs as lists of `0x`-prefixed hex nodes.
+mod data_list {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(items: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(items.iter().map(|item| format!("0x{}", hex::encode(item))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|text| hex::decode(super::parse_hex(text)?).map_err(|err| err.to_string()))
+            .collect::<Result<_, _>>()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// numbers as `0x`-prefixed hex without leading zeros, `0x0` for zero.
+mod quantity {
+    use primitive_types::U256;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, T: Copy + Into<U256>>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:#x}", (*value).into()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: TryFrom<U256>>(deserializer: D) -> Result<T, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let value = parse(&text).map_err(D::Error::custom)?;
+        T::try_from(value).map_err(|_| D::Error::custom(format!("{} is out of range", text)))
+    }
+
+    pub fn parse(text: &str) -> Result<U256, String> {
+        let digits = super::parse_hex(text)?;
+        if digits.is_empty() || digits.len() > 64 {
+            return Err(format!("invalid quantity {:?}", text));
+        }
+        U256::from_str_radix(digits, 16).map_err(|err| format!("invalid quantity {:?}: {}", text, err))
+    }
+}
+
+/// storage keys are written as full 32-byte words, but nodes echo back
+/// whatever form was requested, so shorter quantities are accepted too.
+mod slot {
+    use primitive_types::U256;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(slot: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{}", hex::encode(crate::state::slot_key(*slot))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+        super::quantity::parse(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProofError;
+
+    fn address(byte: u8) -> Address {
+        [byte; 20]
+    }
+
+    /// a committed state with two accounts, one of them with storage.
+    fn state() -> StateTrie {
+        let mut state = StateTrie::new();
+        state.set_account(&address(1), &Account::new(7, U256::from(1_000_000u64))).unwrap();
+        state.set_account(&address(2), &Account::new(1, U256::from(5u64))).unwrap();
+        for slot in 0..20u64 {
+            state.set_storage(&address(1), U256::from(slot), U256::from(slot * 100 + 1)).unwrap();
+        }
+        state.commit().unwrap();
+        state
+    }
+
+    #[test]
+    fn test_account_proof_verifies() {
+        let state = state();
+        let root = state.root_hash();
+        let proof = state
+            .get_account_proof(&address(1), &[U256::from(3u64), U256::from(19u64), U256::from(500u64)])
+            .unwrap();
+
+        assert_eq!(proof.nonce, 7);
+        assert_eq!(proof.storage_hash, state.storage_root(&address(1)).unwrap());
+        let values: Vec<U256> = proof.storage_proof.iter().map(|storage| storage.value).collect();
+        assert_eq!(values, vec![U256::from(301u64), U256::from(1901u64), U256::zero()]);
+        assert_eq!(verify_account_proof(&root, &proof), Ok(()));
+    }
+
+    #[test]
+    fn test_missing_account_proof_verifies() {
+        let state = state();
+        let proof = state.get_account_proof(&address(9), &[U256::one()]).unwrap();
+        assert_eq!(proof.balance, U256::zero());
+        assert_eq!(proof.storage_hash, EMPTY_ROOT);
+        assert_eq!(proof.storage_proof[0].value, U256::zero());
+        assert_eq!(verify_account_proof(&state.root_hash(), &proof), Ok(()));
+
+        let zeroed = AccountProof {
+            storage_hash: [0; 32],
+            code_hash: [0; 32],
+            ..proof.clone()
+        };
+        assert_eq!(verify_account_proof(&state.root_hash(), &zeroed), Ok(()));
+
+        let funded = AccountProof { balance: U256::one(), ..proof };
+        assert_eq!(verify_account_proof(&state.root_hash(), &funded), Err(AccountProofError::AccountMismatch));
+    }
+
+    #[test]
+    fn test_tampered_account_proofs_are_rejected() {
+        let state = state();
+        let root = state.root_hash();
+        let proof = state.get_account_proof(&address(1), &[U256::from(4u64)]).unwrap();
+
+        let richer = AccountProof { balance: proof.balance + 1, ..proof.clone() };
+        assert_eq!(verify_account_proof(&root, &richer), Err(AccountProofError::AccountMismatch));
+
+        let mut altered = proof.clone();
+        altered.storage_proof[0].value = U256::from(7u64);
+        assert_eq!(
+            verify_account_proof(&root, &altered),
+            Err(AccountProofError::StorageMismatch { slot: U256::from(4u64) })
+        );
+
+        // storage proven under a different root than the account's.
+        let mut moved = proof.clone();
+        moved.storage_proof[0].proof = state.get_account_proof(&address(2), &[U256::from(4u64)]).unwrap().storage_proof[0].proof.clone();
+        assert!(matches!(
+            verify_account_proof(&root, &moved),
+            Err(AccountProofError::InvalidProof(ProofError::MissingNode { .. }))
+        ));
+
+        let mut truncated = proof;
+        truncated.account_proof.pop();
+        assert!(matches!(verify_account_proof(&root, &truncated), Err(AccountProofError::InvalidProof(_))));
+    }
+
+    #[test]
+    fn test_account_proof_ignores_pending_storage() {
+        let mut state = state();
+        state.set_storage(&address(1), U256::from(3u64), U256::from(42u64)).unwrap();
+        let proof = state.get_account_proof(&address(1), &[U256::from(3u64)]).unwrap();
+        assert_eq!(proof.storage_proof[0].value, U256::from(301u64));
+        assert_eq!(verify_account_proof(&state.root_hash(), &proof), Ok(()));
+    }
+
+    #[test]
+    fn test_account_proof_json() {
+        let state = state();
+        let proof = state.get_account_proof(&address(2), &[U256::from(0x10u64)]).unwrap();
+        let json = serde_json::to_value(&proof).unwrap();
+
+        assert_eq!(json["address"], format!("0x{}", "02".repeat(20)));
+        assert_eq!(json["balance"], "0x5");
+        assert_eq!(json["nonce"], "0x1");
+        assert_eq!(json["codeHash"], format!("0x{}", hex::encode(EMPTY_CODE_HASH)));
+        assert_eq!(json["storageHash"], format!("0x{}", hex::encode(EMPTY_ROOT)));
+        assert_eq!(json["storageProof"][0]["key"], format!("0x{}10", "0".repeat(62)));
+        assert_eq!(json["storageProof"][0]["value"], "0x0");
+        assert!(json["accountProof"][0].as_str().unwrap().starts_with("0x"));
+
+        let parsed: AccountProof = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, proof);
+    }
+
+    #[test]
+    fn test_account_proof_from_node_json() {
+        let state = state();
+        let proof = state.get_account_proof(&address(1), &[U256::from(2u64)]).unwrap();
+        let storage_proof: Vec<String> = proof.storage_proof[0].proof.iter().map(|node| format!("\"0x{}\"", hex::encode(node))).collect();
+        let account_proof: Vec<String> = proof.account_proof.iter().map(|node| format!("\"0x{}\"", hex::encode(node))).collect();
+
+        // keys echoed back in the short form they were requested in.
+        let json = format!(
+            r#"{{
+                "address": "0x{}",
+                "accountProof": [{}],
+                "balance": "0xf4240",
+                "codeHash": "0x{}",
+                "nonce": "0x7",
+                "storageHash": "0x{}",
+                "storageProof": [{{ "key": "0x2", "value": "0xc9", "proof": [{}] }}]
+            }}"#,
+            "01".repeat(20),
+            account_proof.join(","),
+            hex::encode(EMPTY_CODE_HASH),
+            hex::encode(proof.storage_hash),
+            storage_proof.join(","),
+        );
+        let parsed: AccountProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, proof);
+        assert_eq!(verify_account_proof(&state.root_hash(), &parsed), Ok(()));
+    }
+
+    #[test]
+    fn test_account_proof_json_rejects_malformed_fields() {
+        let state = state();
+        let mut json = serde_json::to_value(state.get_account_proof(&address(2), &[]).unwrap()).unwrap();
+        json["nonce"] = "0x10000000000000000".into();
+        assert!(serde_json::from_value::<AccountProof>(json.clone()).is_err());
+        json["nonce"] = "12".into();
+        assert!(serde_json::from_value::<AccountProof>(json.clone()).is_err());
+        json["nonce"] = "0x1".into();
+        json["codeHash"] = "0x1234".into();
+        assert!(serde_json::from_value::<AccountProof>(json).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+
+use crate::error::TrieError;
+use crate::nibbles::Nibbles;
+use crate::node::{keccak256, Node};
+use crate::trie::EMPTY_ROOT;
+
+/// proofs retained by a `HashBuilder`, keyed by target key.
+pub type Proofs = BTreeMap<Vec<u8>, Vec<Vec<u8>>>;
+
+/// a branch on the path of the last key that may still gain children.
+struct Frame {
+    depth: usize,
+    children: [Box<Node>; 16],
+    value: Option<Vec<u8>>,
+}
+
+/// the finished part of the trie below the branch point of the last two
+/// keys: either just the last entry, or a completed branch.
+enum Subtree {
+    Leaf(Vec<u8>),
+    Branch(Frame),
+}
+
+/// computes a trie root from `(key, value)` pairs added in ascending key
+/// order without building the trie.
+///
+/// only the branches on the path of the most recent key are kept, with each
+/// finished child reduced to its hash or small inline node, so memory is
+/// O(key length) regardless of the number of entries. the root matches
+/// `EthTrie::root_hash` for the same entries.
+///
+/// proof targets given to `with_proof_targets` get the same proof that
+/// `EthTrie::get_proof` would return, collected as the nodes are finished.
+#[derive(Default)]
+pub struct HashBuilder {
+    stack: Vec<Frame>,
+    last: Option<(Vec<u8>, Vec<u8>)>,
+    targets: Vec<(Vec<u8>, Vec<u8>)>,
+    proofs: Vec<Vec<(usize, Vec<u8>)>>,
+}
+
+impl HashBuilder {
+    pub fn new() -> Self {
+        HashBuilder::default()
+    }
+
+    /// a builder that also retains the proof of each key in `keys`, whether
+    /// or not it ends up in the trie.
+    pub fn with_proof_targets<I>(keys: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let targets: Vec<(Vec<u8>, Vec<u8>)> = keys
+            .into_iter()
+            .map(|key| {
+                let key = key.as_ref().to_vec();
+                let nibbles = Nibbles::from_raw(&key, false).as_slice().to_vec();
+                (key, nibbles)
+            })
+            .collect();
+        HashBuilder {
+            proofs: vec![Vec::new(); targets.len()],
+            targets,
+            ..HashBuilder::default()
+        }
+    }
+
+    /// adds the next entry. keys must be strictly ascending.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError> {
+        let nibbles = Nibbles::from_raw(key, false).as_slice().to_vec();
+
+        if let Some((last_key, last_value)) = self.last.take() {
+            if nibbles <= last_key {
+                self.last = Some((last_key, last_value));
+                return Err(TrieError::KeyOutOfOrder(key.to_vec()));
+            }
+
+            let depth = common_prefix_len(&last_key, &nibbles);
+            if last_key.len() == depth {
+                // the last key is a prefix of this one, so its value sits
+                // in the branch where the two part.
+                self.stack.push(Frame {
+                    depth,
+                    children: Default::default(),
+                    value: Some(last_value),
+                });
+            } else {
+                let subtree = self.close_from(depth + 1, &last_key, last_value);
+                let child = self.attach(subtree, &last_key, depth + 1);
+                match self.stack.last_mut() {
+                    Some(frame) if frame.depth == depth => {
+                        *frame.children[last_key[depth] as usize] = child;
+                    }
+                    _ => {
+                        let mut children: [Box<Node>; 16] = Default::default();
+                        *children[last_key[depth] as usize] = child;
+                        self.stack.push(Frame { depth, children, value: None });
+                    }
+                }
+            }
+        }
+
+        self.last = Some((nibbles, value.to_vec()));
+        Ok(())
+    }
+
+    /// the root of every entry added so far.
+    pub fn finish(self) -> [u8; 32] {
+        self.finish_with_proofs().0
+    }
+
+    /// the root, plus the proof of each target key, keyed by the target.
+    pub fn finish_with_proofs(mut self) -> ([u8; 32], Proofs) {
+        let root = match self.last.take() {
+            None => {
+                for proof in &mut self.proofs {
+                    proof.push((0, rlp::encode(&Node::Null).to_vec()));
+                }
+                EMPTY_ROOT
+            }
+            Some((key, value)) => {
+                let subtree = self.close_from(0, &key, value);
+                // unlike every other node, the root is hashed even when it
+                // is small enough to embed.
+                match self.attach(subtree, &key, 0) {
+                    Node::Hash(hash) => hash,
+                    node => keccak256(node.reference()),
+                }
+            }
+        };
+
+        let proofs = self
+            .targets
+            .into_iter()
+            .zip(self.proofs)
+            .map(|((key, _), mut proof)| {
+                proof.sort_by_key(|(depth, _)| *depth);
+                (key, proof.into_iter().map(|(_, node)| node).collect())
+            })
+            .collect();
+        (root, proofs)
+    }
+
+    /// finishes every frame at `depth` or deeper along `key`, the path of the
+    /// last entry, and returns the topmost finished part.
+    fn close_from(&mut self, depth: usize, key: &[u8], value: Vec<u8>) -> Subtree {
+        let mut subtree = Subtree::Leaf(value);
+        while let Some(mut frame) = self.stack.pop_if(|frame| frame.depth >= depth) {
+            let child = self.attach(subtree, key, frame.depth + 1);
+            *frame.children[key[frame.depth] as usize] = child;
+            subtree = Subtree::Branch(frame);
+        }
+        subtree
+    }
+
+    /// the node for `subtree` placed at `key[..at]`, with an extension or
+    /// leaf path covering the nibbles between. nodes that encode to 32 bytes
+    /// or more are reduced to their hash.
+    fn attach(&mut self, subtree: Subtree, key: &[u8], at: usize) -> Node {
+        let node = match subtree {
+            Subtree::Leaf(value) => Node::leaf(key[at..].to_vec(), value),
+            Subtree::Branch(frame) if frame.depth > at => {
+                let prefix = key[at..frame.depth].to_vec();
+                let branch = self.finish_branch(frame, key);
+                Node::extension(prefix, Box::new(branch))
+            }
+            Subtree::Branch(frame) => return self.finish_branch(frame, key),
+        };
+        self.record(&node, &key[..at]);
+        Self::collapse(node)
+    }
+
+    fn finish_branch(&mut self, frame: Frame, key: &[u8]) -> Node {
+        let path = &key[..frame.depth];
+        if !self.targets.is_empty() {
+            // a proof that runs into an empty slot ends with the empty node,
+            // as `EthTrie::get_proof` returns it.
+            for (target, proof) in self.targets.iter().zip(&mut self.proofs) {
+                let (_, nibbles) = target;
+                if nibbles.len() > path.len()
+                    && nibbles.starts_with(path)
+                    && matches!(*frame.children[nibbles[path.len()] as usize], Node::Null)
+                {
+                    proof.push((path.len() + 1, rlp::encode(&Node::Null).to_vec()));
+                }
+            }
+        }
+
+        let node = Node::branch(frame.children, frame.value);
+        self.record(&node, path);
+        Self::collapse(node)
+    }
+
+    /// adds `node`, found at `path`, to the proof of every target below it.
+    fn record(&mut self, node: &Node, path: &[u8]) {
+        let mut encoded = None;
+        for ((_, nibbles), proof) in self.targets.iter().zip(&mut self.proofs) {
+            if nibbles.starts_with(path) {
+                let encoded = encoded.get_or_insert_with(|| rlp::encode(node).to_vec());
+                proof.push((path.len(), encoded.clone()));
+            }
+        }
+    }
+
+    fn collapse(node: Node) -> Node {
+        let reference = node.reference();
+        if reference.len() == 32 {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(reference);
+            Node::Hash(hash)
+        } else {
+            node
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trie::{hashed_key, EthTrie};
+
+    fn build(entries: &[(Vec<u8>, Vec<u8>)]) -> [u8; 32] {
+        let mut sorted = entries.to_vec();
+        sorted.sort();
+        let mut builder = HashBuilder::new();
+        for (key, value) in &sorted {
+            builder.add(key, value).unwrap();
+        }
+        builder.finish()
+    }
+
+    fn trie_root(entries: &[(Vec<u8>, Vec<u8>)]) -> [u8; 32] {
+        let mut trie = EthTrie::new();
+        for (key, value) in entries {
+            trie.insert(key, value).unwrap();
+        }
+        trie.root_hash()
+    }
+
+    fn entries(pairs: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        pairs.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn test_empty_builder() {
+        assert_eq!(HashBuilder::new().finish(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_single_entry() {
+        let single = entries(&[("A", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")]);
+        assert_eq!(build(&single), trie_root(&single));
+        let small = entries(&[("a", "b")]);
+        assert_eq!(build(&small), trie_root(&small));
+    }
+
+    #[test]
+    fn test_known_vector() {
+        let entries = entries(&[("doe", "reindeer"), ("dog", "puppy"), ("dogglesworth", "cat")]);
+        assert_eq!(
+            hex::encode(build(&entries)),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+    }
+
+    #[test]
+    fn test_matches_trie_with_prefix_keys_and_small_nodes() {
+        let cases = [
+            entries(&[("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion")]),
+            entries(&[("a", "1"), ("ab", "2"), ("abc", "3"), ("abd", "4"), ("b", "5")]),
+            entries(&[("", "root value"), ("x", "y")]),
+            entries(&[("key1", ""), ("key2", "v")]),
+        ];
+        for case in &cases {
+            assert_eq!(build(case), trie_root(case), "{:?}", case);
+        }
+    }
+
+    #[test]
+    fn test_matches_trie_for_many_keys() {
+        for count in [2u32, 17, 256, 1000] {
+            let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..count)
+                .map(|i| (hashed_key(i), format!("value-{}", i).into_bytes()))
+                .collect();
+            assert_eq!(build(&entries), trie_root(&entries), "{} keys", count);
+        }
+    }
+
+    #[test]
+    fn test_rejects_keys_out_of_order() {
+        let mut builder = HashBuilder::new();
+        builder.add(b"b", b"1").unwrap();
+        assert!(matches!(builder.add(b"a", b"2"), Err(TrieError::KeyOutOfOrder(key)) if key == b"a"));
+        assert!(matches!(builder.add(b"b", b"3"), Err(TrieError::KeyOutOfOrder(_))));
+
+        // a rejected key leaves the builder as it was.
+        builder.add(b"c", b"4").unwrap();
+        assert_eq!(builder.finish(), trie_root(&entries(&[("b", "1"), ("c", "4")])));
+    }
+
+    #[test]
+    fn test_proofs_match_trie_proofs() {
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = (0..200u32)
+            .map(|i| (keccak256(&i.to_be_bytes())[..4].to_vec(), vec![i as u8; 1 + i as usize % 40]))
+            .collect();
+        entries.extend(self::entries(&[("do", "verb"), ("dog", "puppy")]));
+        entries.sort();
+
+        let mut targets: Vec<Vec<u8>> = entries.iter().step_by(7).map(|(key, _)| key.clone()).collect();
+        // absent keys: past a leaf, into an empty slot, and inside an extension.
+        targets.extend([b"doge".to_vec(), vec![0xff; 5], b"d".to_vec(), Vec::new()]);
+
+        let mut builder = HashBuilder::with_proof_targets(&targets);
+        for (key, value) in &entries {
+            builder.add(key, value).unwrap();
+        }
+        let (root, proofs) = builder.finish_with_proofs();
+
+        let trie = {
+            let mut trie = EthTrie::new();
+            for (key, value) in &entries {
+                trie.insert(key, value).unwrap();
+            }
+            trie
+        };
+        assert_eq!(root, trie.root_hash());
+        assert_eq!(proofs.len(), targets.len());
+        for target in &targets {
+            let proof = &proofs[target];
+            assert_eq!(proof, &trie.get_proof(target).unwrap(), "proof of {:?}", target);
+            assert_eq!(
+                EthTrie::verify_proof(&root, target, proof).unwrap(),
+                trie.get(target).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_proofs_of_empty_and_single_entry_builders() {
+        let (root, proofs) = HashBuilder::with_proof_targets([b"k"]).finish_with_proofs();
+        assert_eq!(root, EMPTY_ROOT);
+        assert_eq!(proofs[b"k".as_slice()], EthTrie::new().get_proof(b"k").unwrap());
+
+        let mut builder = HashBuilder::with_proof_targets([b"k", b"j"]);
+        builder.add(b"k", b"v").unwrap();
+        let (root, proofs) = builder.finish_with_proofs();
+        let mut trie = EthTrie::new();
+        trie.insert(b"k", b"v").unwrap();
+        assert_eq!(root, trie.root_hash());
+        assert_eq!(proofs[b"k".as_slice()], trie.get_proof(b"k").unwrap());
+        assert_eq!(proofs[b"j".as_slice()], trie.get_proof(b"j").unwrap());
+    }
+}
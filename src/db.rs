@@ -0,0 +1,456 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::error::TrieError;
+use crate::trie::EMPTY_ROOT;
+
+/// storage for rlp-encoded trie nodes, keyed by the keccak256 hash of the
+/// encoding. only nodes that are referenced by hash (32 bytes or more once
+/// encoded, plus the root) are ever written; smaller nodes stay embedded in
+/// their parent.
+pub trait NodeDb {
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, TrieError>;
+
+    fn put(&mut self, hash: [u8; 32], node: Vec<u8>) -> Result<(), TrieError>;
+
+    fn remove(&mut self, hash: &[u8; 32]) -> Result<(), TrieError>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDb {
+    nodes: HashMap<[u8; 32], Vec<u8>>,
+}
+
+impl MemoryDb {
+    pub fn new() -> Self {
+        MemoryDb {
+            nodes: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// stores `node` under `hash`. unlike `NodeDb::put` this cannot fail.
+    pub fn insert(&mut self, hash: [u8; 32], node: Vec<u8>) {
+        self.nodes.insert(hash, node);
+    }
+}
+
+impl NodeDb for MemoryDb {
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, TrieError> {
+        Ok(self.nodes.get(hash).cloned())
+    }
+
+    fn put(&mut self, hash: [u8; 32], node: Vec<u8>) -> Result<(), TrieError> {
+        self.insert(hash, node);
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &[u8; 32]) -> Result<(), TrieError> {
+        self.nodes.remove(hash);
+        Ok(())
+    }
+}
+
+/// a handle to a database that several tries use at once, e.g. the state
+/// trie and every account's storage trie. clones share the same database.
+pub struct SharedDb<D> {
+    inner: Rc<RefCell<D>>,
+}
+
+impl<D> SharedDb<D> {
+    pub fn new(db: D) -> Self {
+        SharedDb {
+            inner: Rc::new(RefCell::new(db)),
+        }
+    }
+
+    /// unwraps the database, or returns the handle if it is still shared.
+    pub fn into_inner(self) -> Result<D, Self> {
+        Rc::try_unwrap(self.inner)
+            .map(RefCell::into_inner)
+            .map_err(|inner| SharedDb { inner })
+    }
+}
+
+impl<D> Clone for SharedDb<D> {
+    fn clone(&self) -> Self {
+        SharedDb {
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+impl<D: NodeDb> NodeDb for SharedDb<D> {
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.inner.borrow().get(hash)
+    }
+
+    fn put(&mut self, hash: [u8; 32], node: Vec<u8>) -> Result<(), TrieError> {
+        self.inner.borrow_mut().put(hash, node)
+    }
+
+    fn remove(&mut self, hash: &[u8; 32]) -> Result<(), TrieError> {
+        self.inner.borrow_mut().remove(hash)
+    }
+}
+
+const FILE_MAGIC: &[u8; 8] = b"MPTNODE1";
+const HEADER_LEN: u64 = 8 + 32;
+const RECORD_HEADER_LEN: u64 = 32 + 4;
+const TOMBSTONE: u32 = u32::MAX;
+
+/// an append-only node log on disk.
+///
+/// the file starts with a header holding a magic tag and the current root
+/// hash, followed by records of `hash || len (u32 le) || rlp`. removals are
+/// appended as tombstone records with `len = u32::MAX`. the hash -> offset
+/// index is kept in memory and rebuilt by scanning the log on open; a record
+/// cut short by a crash is dropped and the file truncated back to the last
+/// complete record.
+#[derive(Debug)]
+pub struct FileDb {
+    file: File,
+    index: HashMap<[u8; 32], (u64, u32)>,
+    root: [u8; 32],
+    end: u64,
+}
+
+impl FileDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, TrieError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(FILE_MAGIC)?;
+            file.write_all(&EMPTY_ROOT)?;
+            return Ok(FileDb {
+                file,
+                index: HashMap::new(),
+                root: EMPTY_ROOT,
+                end: HEADER_LEN,
+            });
+        }
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(0))?;
+        let header_ok = file.metadata()?.len() >= HEADER_LEN
+            && file.read_exact(&mut header).is_ok()
+            && &header[..8] == FILE_MAGIC;
+        if !header_ok {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "not a trie node file").into());
+        }
+        let mut root = [0u8; 32];
+        root.copy_from_slice(&header[8..]);
+
+        let mut db = FileDb {
+            file,
+            index: HashMap::new(),
+            root,
+            end: HEADER_LEN,
+        };
+        db.rebuild_index()?;
+        Ok(db)
+    }
+
+    /// the root hash recorded by the last `set_root`.
+    pub fn root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    pub fn set_root(&mut self, root: [u8; 32]) -> Result<(), TrieError> {
+        self.file.seek(SeekFrom::Start(8))?;
+        self.file.write_all(&root)?;
+        self.file.sync_data()?;
+        self.root = root;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    fn rebuild_index(&mut self) -> Result<(), TrieError> {
+        let file_len = self.file.metadata()?.len();
+        let mut offset = HEADER_LEN;
+        let mut record_header = [0u8; RECORD_HEADER_LEN as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        while offset + RECORD_HEADER_LEN <= file_len {
+            self.file.read_exact(&mut record_header)?;
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&record_header[..32]);
+            let mut len_bytes = [0u8; 4];
+            len_bytes.copy_from_slice(&record_header[32..]);
+            let len = u32::from_le_bytes(len_bytes);
+
+            if len == TOMBSTONE {
+                self.index.remove(&hash);
+                offset += RECORD_HEADER_LEN;
+                continue;
+            }
+
+            let data_offset = offset + RECORD_HEADER_LEN;
+            if data_offset + len as u64 > file_len {
+                break;
+            }
+            self.index.insert(hash, (data_offset, len));
+            offset = data_offset + len as u64;
+            self.file.seek(SeekFrom::Start(offset))?;
+        }
+
+        if offset != file_len {
+            self.file.set_len(offset)?;
+        }
+        self.end = offset;
+        Ok(())
+    }
+
+    fn append(&mut self, hash: &[u8; 32], len: u32, data: &[u8]) -> Result<(), TrieError> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + data.len());
+        record.extend_from_slice(hash);
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(data);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&record)?;
+        self.end += record.len() as u64;
+        Ok(())
+    }
+}
+
+impl NodeDb for FileDb {
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, TrieError> {
+        let Some(&(offset, len)) = self.index.get(hash) else {
+            return Ok(None);
+        };
+
+        let mut data = vec![0u8; len as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn put(&mut self, hash: [u8; 32], node: Vec<u8>) -> Result<(), TrieError> {
+        // nodes are content addressed, so a hash already in the log never
+        // needs to be written again.
+        if self.index.contains_key(&hash) {
+            return Ok(());
+        }
+
+        let len = u32::try_from(node.len())
+            .ok()
+            .filter(|len| *len != TOMBSTONE)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "node too large"))?;
+        self.append(&hash, len, &node)?;
+        self.index.insert(hash, (self.end - node.len() as u64, len));
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &[u8; 32]) -> Result<(), TrieError> {
+        if self.index.remove(hash).is_some() {
+            self.append(hash, TOMBSTONE, &[])?;
+        }
+        Ok(())
+    }
+}
+
+/// a `MemoryDb` that counts reads, so tests can check which nodes a walk
+/// loaded.
+#[cfg(test)]
+pub(crate) struct CountingDb {
+    pub(crate) inner: MemoryDb,
+    pub(crate) reads: std::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+impl CountingDb {
+    pub(crate) fn new(inner: MemoryDb) -> Self {
+        CountingDb { inner, reads: std::cell::Cell::new(0) }
+    }
+}
+
+#[cfg(test)]
+impl NodeDb for CountingDb {
+    fn get(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.reads.set(self.reads.get() + 1);
+        self.inner.get(hash)
+    }
+
+    fn put(&mut self, hash: [u8; 32], data: Vec<u8>) -> Result<(), TrieError> {
+        self.inner.put(hash, data)
+    }
+
+    fn remove(&mut self, hash: &[u8; 32]) -> Result<(), TrieError> {
+        self.inner.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_db_put_get_remove() {
+        let mut db = MemoryDb::new();
+        assert!(db.is_empty());
+
+        db.put([0x01; 32], vec![0xc0]).unwrap();
+        assert_eq!(db.get(&[0x01; 32]).unwrap(), Some(vec![0xc0]));
+        assert_eq!(db.get(&[0x02; 32]).unwrap(), None);
+        assert_eq!(db.len(), 1);
+
+        db.remove(&[0x01; 32]).unwrap();
+        assert_eq!(db.get(&[0x01; 32]).unwrap(), None);
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn test_memory_db_put_overwrites() {
+        let mut db = MemoryDb::new();
+        db.put([0x01; 32], vec![0x01]).unwrap();
+        db.put([0x01; 32], vec![0x02]).unwrap();
+        assert_eq!(db.get(&[0x01; 32]).unwrap(), Some(vec![0x02]));
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn test_shared_db_handles_see_each_others_writes() {
+        let mut first = SharedDb::new(MemoryDb::new());
+        let mut second = first.clone();
+
+        first.put([0x01; 32], vec![0x01]).unwrap();
+        second.put([0x02; 32], vec![0x02]).unwrap();
+        assert_eq!(second.get(&[0x01; 32]).unwrap(), Some(vec![0x01]));
+
+        let first = first.into_inner().unwrap_err();
+        drop(second);
+        let db = first.into_inner().ok().unwrap();
+        assert_eq!(db.len(), 2);
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("merkle-trie-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_file_db_fresh_file_has_empty_root() {
+        let path = temp_path("fresh");
+        let db = FileDb::open(&path).unwrap();
+        assert_eq!(db.root(), EMPTY_ROOT);
+        assert!(db.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_db_persists_nodes_and_root() {
+        let path = temp_path("persist");
+        {
+            let mut db = FileDb::open(&path).unwrap();
+            db.put([0x01; 32], vec![0xc1, 0x01]).unwrap();
+            db.put([0x02; 32], vec![0xc1, 0x02]).unwrap();
+            db.set_root([0x02; 32]).unwrap();
+        }
+
+        let db = FileDb::open(&path).unwrap();
+        assert_eq!(db.root(), [0x02; 32]);
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(&[0x01; 32]).unwrap(), Some(vec![0xc1, 0x01]));
+        assert_eq!(db.get(&[0x02; 32]).unwrap(), Some(vec![0xc1, 0x02]));
+        assert_eq!(db.get(&[0x03; 32]).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_db_put_is_append_only_and_deduplicated() {
+        let path = temp_path("dedup");
+        let mut db = FileDb::open(&path).unwrap();
+        db.put([0x01; 32], vec![0xAA; 40]).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        db.put([0x01; 32], vec![0xAA; 40]).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+
+        db.put([0x02; 32], vec![0xBB; 40]).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size + RECORD_HEADER_LEN + 40);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_db_remove_survives_reopen() {
+        let path = temp_path("remove");
+        {
+            let mut db = FileDb::open(&path).unwrap();
+            db.put([0x01; 32], vec![0x01]).unwrap();
+            db.put([0x02; 32], vec![0x02]).unwrap();
+            db.remove(&[0x01; 32]).unwrap();
+            assert_eq!(db.get(&[0x01; 32]).unwrap(), None);
+        }
+
+        let db = FileDb::open(&path).unwrap();
+        assert_eq!(db.get(&[0x01; 32]).unwrap(), None);
+        assert_eq!(db.get(&[0x02; 32]).unwrap(), Some(vec![0x02]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_db_drops_truncated_record() {
+        let path = temp_path("truncated");
+        {
+            let mut db = FileDb::open(&path).unwrap();
+            db.put([0x01; 32], vec![0xAA; 40]).unwrap();
+            db.put([0x02; 32], vec![0xBB; 40]).unwrap();
+        }
+        let full = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full - 10).unwrap();
+        drop(file);
+
+        let mut db = FileDb::open(&path).unwrap();
+        assert_eq!(db.get(&[0x01; 32]).unwrap(), Some(vec![0xAA; 40]));
+        assert_eq!(db.get(&[0x02; 32]).unwrap(), None);
+
+        db.put([0x03; 32], vec![0xCC; 8]).unwrap();
+        drop(db);
+        let db = FileDb::open(&path).unwrap();
+        assert_eq!(db.get(&[0x03; 32]).unwrap(), Some(vec![0xCC; 8]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_db_rejects_short_file() {
+        let path = temp_path("short");
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(matches!(FileDb::open(&path), Err(TrieError::Io(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_db_rejects_foreign_file() {
+        let path = temp_path("foreign");
+        std::fs::write(&path, b"{\"root\": null, \"padding\": \"..................\"}").unwrap();
+        assert!(matches!(FileDb::open(&path), Err(TrieError::Io(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,374 @@
+use std::borrow::Cow;
+
+use crate::db::NodeDb;
+use crate::error::TrieError;
+use crate::iter::TrieIter;
+use crate::nibbles::nibbles_to_bytes;
+use crate::node::Node;
+use crate::trie::EthTrie;
+
+/// a key whose value differs between two tries, as yielded by
+/// `EthTrie::diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added { key: Vec<u8>, value: Vec<u8> },
+    Removed { key: Vec<u8>, value: Vec<u8> },
+    Modified { key: Vec<u8>, old: Vec<u8>, new: Vec<u8> },
+}
+
+impl Change {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Change::Added { key, .. } | Change::Removed { key, .. } | Change::Modified { key, .. } => key,
+        }
+    }
+
+    fn between(key: Vec<u8>, old: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Option<Self> {
+        match (old, new) {
+            (None, None) => None,
+            (None, Some(value)) => Some(Change::Added { key, value }),
+            (Some(value), None) => Some(Change::Removed { key, value }),
+            (Some(old), Some(new)) if old == new => None,
+            (Some(old), Some(new)) => Some(Change::Modified { key, old, new }),
+        }
+    }
+}
+
+/// one side of a frame: a node, entered `offset` nibbles into the key of a
+/// leaf or the prefix of an extension when the other side branches inside
+/// it. only nodes entered at offset 0 are compared by reference.
+struct Side<'a> {
+    node: Cow<'a, Node>,
+    offset: usize,
+}
+
+impl<'a> Side<'a> {
+    fn new(node: Cow<'a, Node>) -> Option<Self> {
+        (!matches!(*node, Node::Null)).then_some(Side { node, offset: 0 })
+    }
+
+    fn resolve<D: NodeDb>(self, trie: &EthTrie<D>) -> Result<Self, TrieError> {
+        match *self.node {
+            Node::Hash(hash) => Ok(Side { node: Cow::Owned(trie.resolve(&hash)?), offset: 0 }),
+            _ => Ok(self),
+        }
+    }
+
+    /// splits a resolved node one nibble down: the value stored at its own
+    /// path and the sides below each of its 16 children.
+    fn expand(self) -> (Option<Vec<u8>>, [Option<Side<'a>>; 16]) {
+        let mut children: [Option<Side<'a>>; 16] = Default::default();
+        let offset = self.offset;
+        match self.node {
+            Cow::Borrowed(Node::Leaf { key, value, .. }) if offset == key.len() => return (Some(value.clone()), children),
+            Cow::Owned(Node::Leaf { key, value, .. }) if offset == key.len() => return (Some(value), children),
+            Cow::Borrowed(Node::Extension { prefix, next, .. }) if offset + 1 == prefix.len() => {
+                children[prefix[offset] as usize] = Side::new(Cow::Borrowed(&**next));
+            }
+            Cow::Owned(Node::Extension { prefix, next, .. }) if offset + 1 == prefix.len() => {
+                children[prefix[offset] as usize] = Side::new(Cow::Owned(*next));
+            }
+            node @ (Cow::Borrowed(Node::Leaf { .. } | Node::Extension { .. }) | Cow::Owned(Node::Leaf { .. } | Node::Extension { .. })) => {
+                let (Node::Leaf { key: path, .. } | Node::Extension { prefix: path, .. }) = &*node else { unreachable!() };
+                let nibble = path[offset] as usize;
+                children[nibble] = Some(Side { node, offset: offset + 1 });
+            }
+            Cow::Borrowed(Node::Branch { children: nodes, value, .. }) => {
+                for (slot, child) in children.iter_mut().zip(nodes.iter()) {
+                    *slot = Side::new(Cow::Borrowed(&**child));
+                }
+                return (value.clone(), children);
+            }
+            Cow::Owned(Node::Branch { children: nodes, value, .. }) => {
+                for (slot, child) in children.iter_mut().zip(nodes) {
+                    *slot = Side::new(Cow::Owned(*child));
+                }
+                return (value, children);
+            }
+            Cow::Borrowed(Node::Null | Node::Hash(_)) | Cow::Owned(Node::Null | Node::Hash(_)) => {}
+        }
+        (None, children)
+    }
+
+    /// every entry below this side, whose position in the trie is `path`.
+    fn walk<D: NodeDb>(self, trie: &'a EthTrie<D>, path: &[u8]) -> TrieIter<'a, D> {
+        // the nibbles already stepped into the node are the tail of `path`.
+        let start = path[..path.len() - self.offset].to_vec();
+        TrieIter::subtree(trie, start, self.node)
+    }
+}
+
+struct Frame<'a> {
+    path: Vec<u8>,
+    old: Option<Side<'a>>,
+    new: Option<Side<'a>>,
+}
+
+/// the subtree present on only one side, yielded entry by entry.
+enum Drain<'a, D, E> {
+    Removed(TrieIter<'a, D>),
+    Added(TrieIter<'a, E>),
+}
+
+/// walk over two tries in step, yielding the changes from the first to the
+/// second in key order.
+///
+/// subtrees whose references match on both sides are skipped without being
+/// visited, so only the paths that differ are loaded from the databases. a
+/// subtree present on one side only is walked in full. iteration stops after
+/// the first error.
+pub struct TrieDiff<'a, D, E> {
+    old: &'a EthTrie<D>,
+    new: &'a EthTrie<E>,
+    stack: Vec<Frame<'a>>,
+    drain: Option<Drain<'a, D, E>>,
+}
+
+impl<'a, D: NodeDb, E: NodeDb> TrieDiff<'a, D, E> {
+    fn new(old: &'a EthTrie<D>, new: &'a EthTrie<E>) -> Self {
+        let mut stack = Vec::new();
+        // the roots are compared by hash, since a committed root is stored
+        // by hash even when it is small enough to be embedded.
+        if old.root_hash() != new.root_hash() {
+            stack.push(Frame {
+                path: Vec::new(),
+                old: Side::new(Cow::Borrowed(old.root_node())),
+                new: Side::new(Cow::Borrowed(new.root_node())),
+            });
+        }
+        TrieDiff { old, new, stack, drain: None }
+    }
+
+    /// the next change from the subtree being drained, if any.
+    fn next_drained(&mut self) -> Option<Result<Change, TrieError>> {
+        let change = match self.drain.as_mut()? {
+            Drain::Removed(iter) => iter.next().map(|entry| entry.map(|(key, value)| Change::Removed { key, value })),
+            Drain::Added(iter) => iter.next().map(|entry| entry.map(|(key, value)| Change::Added { key, value })),
+        };
+        if change.is_none() {
+            self.drain = None;
+        }
+        change
+    }
+
+    fn step(&mut self, frame: Frame<'a>) -> Result<Option<Change>, TrieError> {
+        let Frame { path, old, new } = frame;
+        let (old, new) = match (old, new) {
+            (None, None) => return Ok(None),
+            (Some(old), None) => {
+                self.drain = Some(Drain::Removed(old.walk(self.old, &path)));
+                return Ok(None);
+            }
+            (None, Some(new)) => {
+                self.drain = Some(Drain::Added(new.walk(self.new, &path)));
+                return Ok(None);
+            }
+            (Some(old), Some(new)) => (old, new),
+        };
+
+        if old.offset == 0 && new.offset == 0 && old.node.reference() == new.node.reference() {
+            return Ok(None);
+        }
+
+        let (old_value, old_children) = old.resolve(self.old)?.expand();
+        let (new_value, new_children) = new.resolve(self.new)?.expand();
+        let pairs = old_children.into_iter().zip(new_children).enumerate().rev();
+        for (nibble, (old, new)) in pairs {
+            if old.is_some() || new.is_some() {
+                let mut child_path = path.clone();
+                child_path.push(nibble as u8);
+                self.stack.push(Frame { path: child_path, old, new });
+            }
+        }
+        // a value's key is a prefix of every key below it, so it sorts first.
+        Ok(Change::between(nibbles_to_bytes(&path), old_value, new_value))
+    }
+}
+
+impl<'a, D: NodeDb, E: NodeDb> Iterator for TrieDiff<'a, D, E> {
+    type Item = Result<Change, TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.next_drained() {
+                if change.is_err() {
+                    self.stack.clear();
+                    self.drain = None;
+                }
+                return Some(change);
+            }
+
+            let frame = self.stack.pop()?;
+            match self.step(frame) {
+                Ok(Some(change)) => return Some(Ok(change)),
+                Ok(None) => {}
+                Err(err) => {
+                    self.stack.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl<D: NodeDb> EthTrie<D> {
+    /// the changes that turn this trie into `other`, in key order: keys only
+    /// in `other` are added, keys only here are removed, and keys in both
+    /// with different values are modified.
+    pub fn diff<'a, E: NodeDb>(&'a self, other: &'a EthTrie<E>) -> TrieDiff<'a, D, E> {
+        TrieDiff::new(self, other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::db::{CountingDb, MemoryDb};
+    use crate::trie::hashed_key;
+
+    fn trie_of(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> EthTrie {
+        let mut trie = EthTrie::new();
+        for (key, value) in entries {
+            trie.insert(key, value).unwrap();
+        }
+        trie
+    }
+
+    /// the changes between two maps, worked out entry by entry.
+    fn expected(old: &BTreeMap<Vec<u8>, Vec<u8>>, new: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<Change> {
+        let mut keys: Vec<&Vec<u8>> = old.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .filter_map(|key| Change::between(key.clone(), old.get(key).cloned(), new.get(key).cloned()))
+            .collect()
+    }
+
+    fn collect<D: NodeDb, E: NodeDb>(old: &EthTrie<D>, new: &EthTrie<E>) -> Vec<Change> {
+        old.diff(new).collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    fn base_entries() -> BTreeMap<Vec<u8>, Vec<u8>> {
+        (0..500).map(|i| (hashed_key(i), format!("value-{}", i).into_bytes())).collect()
+    }
+
+    fn modified_entries() -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut entries = base_entries();
+        for i in (0..500).step_by(37) {
+            entries.insert(hashed_key(i), b"modified".to_vec());
+        }
+        for i in (5..500).step_by(41) {
+            entries.remove(&hashed_key(i));
+        }
+        for i in 1000..1020 {
+            entries.insert(hashed_key(i), b"added".to_vec());
+        }
+        entries
+    }
+
+    #[test]
+    fn test_diff_matches_entry_comparison() {
+        let (old, new) = (base_entries(), modified_entries());
+        let changes = collect(&trie_of(&old), &trie_of(&new));
+        assert_eq!(changes, expected(&old, &new));
+        assert!(changes.iter().any(|change| matches!(change, Change::Added { .. })));
+        assert!(changes.iter().any(|change| matches!(change, Change::Removed { .. })));
+        assert!(changes.iter().any(|change| matches!(change, Change::Modified { .. })));
+
+        // the reverse diff swaps additions and removals.
+        assert_eq!(collect(&trie_of(&new), &trie_of(&old)), expected(&new, &old));
+    }
+
+    #[test]
+    fn test_diff_of_identical_tries_is_empty() {
+        let trie = trie_of(&base_entries());
+        assert!(collect(&trie, &trie).is_empty());
+        assert!(collect(&trie, &trie_of(&base_entries())).is_empty());
+        assert!(collect(&EthTrie::new(), &EthTrie::new()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_against_empty_trie() {
+        let entries = base_entries();
+        let trie = trie_of(&entries);
+        let added = collect(&EthTrie::new(), &trie);
+        assert_eq!(added, expected(&BTreeMap::new(), &entries));
+        assert_eq!(added.len(), entries.len());
+        assert_eq!(collect(&trie, &EthTrie::new()), expected(&entries, &BTreeMap::new()));
+    }
+
+    #[test]
+    fn test_diff_across_node_shapes() {
+        // prefixes of each other, leaves split into branches, extensions
+        // shortened and branch values appearing or disappearing.
+        let old: BTreeMap<Vec<u8>, Vec<u8>> = [
+            (&b"do"[..], &b"verb"[..]),
+            (b"dog", b"puppy"),
+            (b"horse", b"stallion"),
+            (b"abcdef", b"long shared prefix"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_vec(), value.to_vec()))
+        .collect();
+        let new: BTreeMap<Vec<u8>, Vec<u8>> = [
+            (&b"dog"[..], &b"hound"[..]),
+            (b"doge", b"coin"),
+            (b"horse", b"stallion"),
+            (b"horses", b"herd"),
+            (b"abcdef", b"long shared prefix"),
+            (b"abcxyz", b"diverges late"),
+            (b"a", b"branch value"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_vec(), value.to_vec()))
+        .collect();
+
+        assert_eq!(collect(&trie_of(&old), &trie_of(&new)), expected(&old, &new));
+        assert_eq!(collect(&trie_of(&new), &trie_of(&old)), expected(&new, &old));
+    }
+
+    #[test]
+    fn test_diff_skips_identical_subtrees() {
+        let mut trie = trie_of(&base_entries());
+        let root = trie.commit().unwrap();
+        let db = CountingDb::new(trie.into_db());
+        let stored = db.inner.len();
+        let old = EthTrie::from_root(root, db);
+
+        let mut new = EthTrie::from_root(root, old.db().inner.clone());
+        new.insert(&hashed_key(7), b"changed").unwrap();
+        new.commit().unwrap();
+
+        old.db().reads.set(0);
+        let changes = collect(&old, &new);
+        assert_eq!(changes, vec![Change::Modified { key: hashed_key(7), old: b"value-7".to_vec(), new: b"changed".to_vec() }]);
+        // only the nodes on the changed key's path are loaded.
+        let reads = old.db().reads.get();
+        assert!(reads <= 5 && reads < stored, "{} reads", reads);
+    }
+
+    #[test]
+    fn test_diff_between_committed_and_in_memory_tries() {
+        let (old, new) = (base_entries(), modified_entries());
+        let mut committed = trie_of(&old);
+        let root = committed.commit().unwrap();
+        let committed = EthTrie::from_root(root, committed.into_db());
+
+        assert_eq!(collect(&committed, &trie_of(&new)), expected(&old, &new));
+        assert_eq!(collect(&trie_of(&new), &committed), expected(&new, &old));
+    }
+
+    #[test]
+    fn test_diff_reports_missing_node() {
+        let mut trie = trie_of(&base_entries());
+        let root = trie.commit().unwrap();
+        let old = EthTrie::from_root(root, MemoryDb::new());
+        let new = trie_of(&modified_entries());
+
+        let mut diff = old.diff(&new);
+        assert!(matches!(diff.next(), Some(Err(TrieError::MissingNode(hash))) if hash == root));
+        assert!(diff.next().is_none());
+    }
+}
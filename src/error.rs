@@ -0,0 +1,91 @@
+use primitive_types::U256;
+use rlp::DecoderError;
+use thiserror::Error;
+
+use crate::journal::CheckpointId;
+
+#[derive(Debug, Error)]
+pub enum TrieError {
+    #[error("missing trie node 0x{}", hex::encode(.0))]
+    MissingNode([u8; 32]),
+    #[error("invalid encoding for trie node 0x{}: {source}", hex::encode(.hash))]
+    InvalidNode {
+        hash: [u8; 32],
+        source: DecoderError,
+    },
+    #[error("invalid account record for 0x{}: {source}", hex::encode(.address))]
+    InvalidAccount {
+        address: [u8; 20],
+        source: DecoderError,
+    },
+    #[error("invalid storage value for 0x{}: {source}", hex::encode(.address))]
+    InvalidStorage {
+        address: [u8; 20],
+        source: DecoderError,
+    },
+    #[error("no preimage recorded for hashed key 0x{}", hex::encode(.0))]
+    MissingPreimage(Vec<u8>),
+    #[error("key 0x{} is not after the previous key", hex::encode(.0))]
+    KeyOutOfOrder(Vec<u8>),
+    #[error("key 0x{} does not hold the value it was given", hex::encode(.0))]
+    ValueMismatch(Vec<u8>),
+    #[error("checkpoint {0:?} was already reverted or discarded")]
+    UnknownCheckpoint(CheckpointId),
+    #[error("invalid proof: {0}")]
+    InvalidProof(#[from] ProofError),
+    #[error("database i/o error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// why a proof could not be verified. a proof that is valid but shows the key
+/// is absent is not an error; see `EthTrie::verify_proof`.
+///
+/// `depth` counts the nodes walked from the root (the root is depth 0) and
+/// `index` is the position of the offending item in the proof.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProofError {
+    #[error("proof does not contain node 0x{} referenced at depth {depth}", hex::encode(.hash))]
+    MissingNode { hash: [u8; 32], depth: usize },
+    #[error("proof item {index} at depth {depth} is not a valid trie node: {reason}")]
+    InvalidNode {
+        index: usize,
+        depth: usize,
+        reason: DecoderError,
+    },
+}
+
+impl ProofError {
+    pub fn depth(&self) -> usize {
+        match self {
+            ProofError::MissingNode { depth, .. } | ProofError::InvalidNode { depth, .. } => *depth,
+        }
+    }
+}
+
+/// why a range proof was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RangeProofError {
+    #[error("range proof has {keys} keys but {values} values")]
+    LengthMismatch { keys: usize, values: usize },
+    #[error("range proof keys must be strictly ascending and not before the start key")]
+    UnorderedKeys,
+    #[error("range proof entries do not match the root: entries are missing, extra or altered")]
+    RootMismatch,
+    #[error("invalid range proof: {0}")]
+    InvalidProof(#[from] ProofError),
+}
+
+/// why an `eth_getProof` response was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AccountProofError {
+    #[error("proven account record is invalid: {0}")]
+    InvalidAccount(DecoderError),
+    #[error("account fields do not match the proven account")]
+    AccountMismatch,
+    #[error("proven value of storage slot {slot:#x} is invalid: {source}")]
+    InvalidStorage { slot: U256, source: DecoderError },
+    #[error("value of storage slot {slot:#x} does not match its proof")]
+    StorageMismatch { slot: U256 },
+    #[error("invalid proof: {0}")]
+    InvalidProof(#[from] ProofError),
+}
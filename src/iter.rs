@@ -0,0 +1,552 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+
+use crate::db::NodeDb;
+use crate::error::TrieError;
+use crate::nibbles::{nibbles_to_bytes, Nibbles};
+use crate::node::Node;
+use crate::trie::EthTrie;
+
+type Entry = (Vec<u8>, Vec<u8>);
+
+enum Frame<'a> {
+    /// yield every entry below `node`, whose path from the root is `path`.
+    Walk(Vec<u8>, Cow<'a, Node>),
+    /// yield the entries below `node` whose remaining path is not less than
+    /// `target`, skipping everything to its left without visiting it.
+    Seek(Vec<u8>, Cow<'a, Node>, Vec<u8>),
+}
+
+enum Stop {
+    Never,
+    Prefix(Vec<u8>),
+    Before(Vec<u8>),
+    After(Vec<u8>),
+}
+
+/// depth-first walk over the trie yielding `(key, value)` pairs in
+/// lexicographic key order.
+///
+/// nodes owned by the trie are borrowed; nodes behind a `Node::Hash` are
+/// loaded from the database as the walk reaches them. iteration stops after
+/// the first error.
+pub struct TrieIter<'a, D> {
+    trie: &'a EthTrie<D>,
+    stack: Vec<Frame<'a>>,
+    skip: Option<Vec<u8>>,
+    stop: Stop,
+    /// nibbles of the stop key, used to drop whole subtrees past the end.
+    stop_path: Vec<u8>,
+}
+
+impl<'a, D: NodeDb> TrieIter<'a, D> {
+    pub(crate) fn new(trie: &'a EthTrie<D>) -> Self {
+        TrieIter {
+            trie,
+            stack: vec![Frame::Walk(Vec::new(), Cow::Borrowed(trie.root_node()))],
+            skip: None,
+            stop: Stop::Never,
+            stop_path: Vec::new(),
+        }
+    }
+
+    /// walks only the entries below `node`, whose path from the root is
+    /// `path`.
+    pub(crate) fn subtree(trie: &'a EthTrie<D>, path: Vec<u8>, node: Cow<'a, Node>) -> Self {
+        TrieIter {
+            trie,
+            stack: vec![Frame::Walk(path, node)],
+            skip: None,
+            stop: Stop::Never,
+            stop_path: Vec::new(),
+        }
+    }
+
+    fn seek(trie: &'a EthTrie<D>, start: &[u8], stop: Stop) -> Self {
+        let target = Nibbles::from_raw(start, false).as_slice().to_vec();
+        let mut iter = TrieIter {
+            trie,
+            stack: vec![Frame::Seek(Vec::new(), Cow::Borrowed(trie.root_node()), target)],
+            skip: None,
+            stop: Stop::Never,
+            stop_path: Vec::new(),
+        };
+        iter.set_stop(stop);
+        iter
+    }
+
+    fn set_stop(&mut self, stop: Stop) {
+        self.stop_path = match &stop {
+            Stop::Never => Vec::new(),
+            Stop::Prefix(key) | Stop::Before(key) | Stop::After(key) => {
+                Nibbles::from_raw(key, false).as_slice().to_vec()
+            }
+        };
+        self.stop = stop;
+    }
+
+    fn joined(path: &[u8], suffix: &[u8]) -> Vec<u8> {
+        let mut joined = Vec::with_capacity(path.len() + suffix.len());
+        joined.extend_from_slice(path);
+        joined.extend_from_slice(suffix);
+        joined
+    }
+
+    fn is_past_end(&self, key: &[u8]) -> bool {
+        match &self.stop {
+            Stop::Never => false,
+            Stop::Prefix(prefix) => !key.starts_with(prefix),
+            Stop::Before(end) => key >= end.as_slice(),
+            Stop::After(end) => key > end.as_slice(),
+        }
+    }
+
+    /// whether every key below `path` is past the stop bound, so the walk
+    /// can end without loading the subtree.
+    fn is_path_past_end(&self, path: &[u8]) -> bool {
+        let shared = path.len().min(self.stop_path.len());
+        match &self.stop {
+            Stop::Never => false,
+            Stop::Prefix(_) => path[..shared] != self.stop_path[..shared],
+            Stop::Before(_) | Stop::After(_) => match path[..shared].cmp(&self.stop_path[..shared]) {
+                Ordering::Less => false,
+                Ordering::Greater => true,
+                // keys below `path` all start with the stop key; only the
+                // stop key itself can still be in bounds.
+                Ordering::Equal => match self.stop {
+                    Stop::Before(_) => path.len() >= self.stop_path.len(),
+                    _ => path.len() > self.stop_path.len(),
+                },
+            },
+        }
+    }
+
+    /// advances the walk to the next entry in key order, ignoring bounds.
+    fn next_entry(&mut self) -> Option<Result<Entry, TrieError>> {
+        while let Some(frame) = self.stack.pop() {
+            let path = match &frame {
+                Frame::Walk(path, _) | Frame::Seek(path, _, _) => path,
+            };
+            if self.is_path_past_end(path) {
+                // frames are stacked in key order, so nothing below is
+                // within bounds either.
+                self.stack.clear();
+                return None;
+            }
+
+            let entry = match frame {
+                Frame::Walk(path, node) => self.walk(path, node),
+                Frame::Seek(path, node, target) => self.seek_step(path, node, target),
+            };
+            match entry {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => {}
+                Err(err) => {
+                    self.stack.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+
+    fn walk(&mut self, path: Vec<u8>, node: Cow<'a, Node>) -> Result<Option<Entry>, TrieError> {
+        match node {
+            Cow::Borrowed(Node::Null) | Cow::Owned(Node::Null) => {}
+            Cow::Borrowed(&Node::Hash(hash)) | Cow::Owned(Node::Hash(hash)) => {
+                let resolved = self.trie.resolve(&hash)?;
+                self.stack.push(Frame::Walk(path, Cow::Owned(resolved)));
+            }
+            Cow::Borrowed(Node::Leaf { key, value, .. }) => {
+                let full_path = Self::joined(&path, key);
+                return Ok(Some((nibbles_to_bytes(&full_path), value.clone())));
+            }
+            Cow::Owned(Node::Leaf { key, value, .. }) => {
+                let full_path = Self::joined(&path, &key);
+                return Ok(Some((nibbles_to_bytes(&full_path), value)));
+            }
+            Cow::Borrowed(Node::Extension { prefix, next, .. }) => {
+                self.stack.push(Frame::Walk(Self::joined(&path, prefix), Cow::Borrowed(next)));
+            }
+            Cow::Owned(Node::Extension { prefix, next, .. }) => {
+                self.stack.push(Frame::Walk(Self::joined(&path, &prefix), Cow::Owned(*next)));
+            }
+            Cow::Borrowed(Node::Branch { children, value, .. }) => {
+                for (i, child) in children.iter().enumerate().rev() {
+                    if !matches!(**child, Node::Null) {
+                        self.stack.push(Frame::Walk(Self::joined(&path, &[i as u8]), Cow::Borrowed(child)));
+                    }
+                }
+                // a branch value's key is a prefix of every key below it, so
+                // it sorts first.
+                if let Some(value) = value {
+                    return Ok(Some((nibbles_to_bytes(&path), value.clone())));
+                }
+            }
+            Cow::Owned(Node::Branch { children, value, .. }) => {
+                for (i, child) in children.into_iter().enumerate().rev() {
+                    if !matches!(*child, Node::Null) {
+                        self.stack.push(Frame::Walk(Self::joined(&path, &[i as u8]), Cow::Owned(*child)));
+                    }
+                }
+                if let Some(value) = value {
+                    return Ok(Some((nibbles_to_bytes(&path), value)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn seek_step(
+        &mut self,
+        path: Vec<u8>,
+        node: Cow<'a, Node>,
+        target: Vec<u8>,
+    ) -> Result<Option<Entry>, TrieError> {
+        if target.is_empty() {
+            return self.walk(path, node);
+        }
+
+        match &*node {
+            Node::Null => {}
+            Node::Hash(hash) => {
+                let resolved = self.trie.resolve(hash)?;
+                self.stack.push(Frame::Seek(path, Cow::Owned(resolved), target));
+            }
+            Node::Leaf { key, .. } => {
+                if key.as_slice() >= target.as_slice() {
+                    return self.walk(path, node);
+                }
+            }
+            Node::Extension { prefix, .. } => {
+                let shared = prefix.len().min(target.len());
+                match prefix[..shared].cmp(&target[..shared]) {
+                    Ordering::Less => {}
+                    // everything below sorts after the target: either the
+                    // extension diverges upwards or the target ends inside it.
+                    Ordering::Greater => self.stack.push(Frame::Walk(path, node)),
+                    Ordering::Equal if shared < prefix.len() => self.stack.push(Frame::Walk(path, node)),
+                    Ordering::Equal => {
+                        let (prefix, next) = match node {
+                            Cow::Borrowed(Node::Extension { prefix, next, .. }) => (prefix.clone(), Cow::Borrowed(&**next)),
+                            Cow::Owned(Node::Extension { prefix, next, .. }) => (prefix, Cow::Owned(*next)),
+                            _ => unreachable!(),
+                        };
+                        let remaining = target[prefix.len()..].to_vec();
+                        self.stack.push(Frame::Seek(Self::joined(&path, &prefix), next, remaining));
+                    }
+                }
+            }
+            Node::Branch { .. } => {
+                let idx = target[0] as usize;
+                // the branch value and children left of `idx` sort before
+                // the target and are dropped; children to the right are
+                // walked in full once the seek below `idx` is exhausted.
+                let mut children: Vec<Cow<'a, Node>> = match node {
+                    Cow::Borrowed(Node::Branch { children, .. }) => {
+                        children.iter().map(|child| Cow::Borrowed(&**child)).collect()
+                    }
+                    Cow::Owned(Node::Branch { children, .. }) => {
+                        children.into_iter().map(|child| Cow::Owned(*child)).collect()
+                    }
+                    _ => unreachable!(),
+                };
+                for i in (idx + 1..16).rev() {
+                    let child = std::mem::replace(&mut children[i], Cow::Owned(Node::Null));
+                    if !matches!(&*child, Node::Null) {
+                        self.stack.push(Frame::Walk(Self::joined(&path, &[i as u8]), child));
+                    }
+                }
+                let child = std::mem::replace(&mut children[idx], Cow::Owned(Node::Null));
+                self.stack.push(Frame::Seek(Self::joined(&path, &[idx as u8]), child, target[1..].to_vec()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, D: NodeDb> Iterator for TrieIter<'a, D> {
+    type Item = Result<Entry, TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.next_entry()?;
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+
+            if self.skip.take().is_some_and(|skip| skip == key) {
+                continue;
+            }
+            if self.is_past_end(&key) {
+                self.stack.clear();
+                return None;
+            }
+            return Some(Ok((key, value)));
+        }
+    }
+}
+
+impl<D: NodeDb> EthTrie<D> {
+    /// iterates over every `(key, value)` pair in lexicographic key order.
+    pub fn iter(&self) -> TrieIter<'_, D> {
+        TrieIter::new(self)
+    }
+
+    /// iterates over the entries whose key starts with `prefix`, in key
+    /// order. the walk descends straight to the matching subtree.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> TrieIter<'_, D> {
+        TrieIter::seek(self, prefix, Stop::Prefix(prefix.to_vec()))
+    }
+
+    /// iterates over the entries whose key falls within `range`, in key
+    /// order. the walk starts at the first key not below the start bound
+    /// without visiting anything to its left.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> TrieIter<'_, D> {
+        let stop = match range.end_bound() {
+            Bound::Included(end) => Stop::After(end.as_ref().to_vec()),
+            Bound::Excluded(end) => Stop::Before(end.as_ref().to_vec()),
+            Bound::Unbounded => Stop::Never,
+        };
+
+        match range.start_bound() {
+            Bound::Included(start) => TrieIter::seek(self, start.as_ref(), stop),
+            Bound::Excluded(start) => {
+                let mut iter = TrieIter::seek(self, start.as_ref(), stop);
+                iter.skip = Some(start.as_ref().to_vec());
+                iter
+            }
+            Bound::Unbounded => {
+                let mut iter = TrieIter::new(self);
+                iter.set_stop(stop);
+                iter
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::db::{CountingDb, MemoryDb};
+    use crate::trie::long_value;
+
+    fn sample_entries() -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut entries = BTreeMap::new();
+        for i in 0..200u32 {
+            let key = format!("key{}", i).into_bytes();
+            let value = long_value(&format!("value-{}", i));
+            entries.insert(key, value);
+        }
+        entries.insert(b"k".to_vec(), b"short".to_vec());
+        entries.insert(b"key".to_vec(), b"branch value".to_vec());
+        entries
+    }
+
+    fn sample_trie() -> EthTrie {
+        let mut trie = EthTrie::new();
+        for (key, value) in sample_entries() {
+            trie.insert(&key, &value).unwrap();
+        }
+        trie
+    }
+
+    fn collect_iter<D: NodeDb>(iter: TrieIter<'_, D>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        iter.collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    fn collect<D: NodeDb>(trie: &EthTrie<D>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        trie.iter().collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_iter_empty_trie() {
+        let trie = EthTrie::new();
+        assert!(collect(&trie).is_empty());
+    }
+
+    #[test]
+    fn test_iter_single_key() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"test", b"value").unwrap();
+        assert_eq!(collect(&trie), vec![(b"test".to_vec(), b"value".to_vec())]);
+    }
+
+    #[test]
+    fn test_iter_is_ordered_with_branch_values() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"horse", b"stallion").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"", b"empty").unwrap();
+
+        let keys: Vec<Vec<u8>> = collect(&trie).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(
+            keys,
+            vec![b"".to_vec(), b"do".to_vec(), b"dog".to_vec(), b"doge".to_vec(), b"horse".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_iter_odd_length_intermediate_paths() {
+        let mut trie = EthTrie::new();
+        // these keys first diverge on a low nibble, leaving odd-length
+        // extension and leaf paths.
+        trie.insert(&[0x12, 0x34], b"a").unwrap();
+        trie.insert(&[0x12, 0x35], b"b").unwrap();
+        trie.insert(&[0x13], b"c").unwrap();
+        trie.insert(&[0x12], b"d").unwrap();
+
+        assert_eq!(
+            collect(&trie),
+            vec![
+                (vec![0x12], b"d".to_vec()),
+                (vec![0x12, 0x34], b"a".to_vec()),
+                (vec![0x12, 0x35], b"b".to_vec()),
+                (vec![0x13], b"c".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iter_matches_sorted_map() {
+        let mut trie = EthTrie::new();
+        let mut expected = BTreeMap::new();
+        for i in 0..300u32 {
+            let key = (i.wrapping_mul(2_654_435_761) % 10_007).to_be_bytes().to_vec();
+            let value = format!("value{}", i).into_bytes();
+            trie.insert(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+
+        assert_eq!(collect(&trie), expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_iter_resolves_committed_nodes() {
+        let mut trie = EthTrie::new();
+        let mut expected = BTreeMap::new();
+        for i in 0..100 {
+            let key = format!("key{}", i).into_bytes();
+            let value = long_value(&format!("value-{}", i));
+            trie.insert(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+        let root = trie.commit().unwrap();
+
+        let reopened = EthTrie::from_root(root, trie.into_db());
+        assert_eq!(collect(&reopened), expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_iter_reports_missing_node() {
+        let trie = EthTrie::from_root([0x11; 32], MemoryDb::new());
+        let mut iter = trie.iter();
+        assert!(matches!(iter.next(), Some(Err(TrieError::MissingNode(_)))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_iter_prefix_matches_filtered_map() {
+        let trie = sample_trie();
+        let entries = sample_entries();
+
+        for prefix in [&b""[..], b"k", b"key", b"key1", b"key19", b"key199", b"key1990", b"kex", b"z", &[0x6b, 0x65, 0x79, 0x30]] {
+            let expected: Vec<_> = entries
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            assert_eq!(collect_iter(trie.iter_prefix(prefix)), expected, "prefix {:?}", prefix);
+        }
+    }
+
+    #[test]
+    fn test_iter_prefix_ending_inside_extension() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"abcdef1", b"one").unwrap();
+        trie.insert(b"abcdef2", b"two").unwrap();
+        trie.insert(b"b", b"three").unwrap();
+
+        let keys: Vec<Vec<u8>> = collect_iter(trie.iter_prefix(b"abc")).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"abcdef1".to_vec(), b"abcdef2".to_vec()]);
+        assert!(collect_iter(trie.iter_prefix(b"abd")).is_empty());
+        assert!(collect_iter(trie.iter_prefix(b"abcdef1x")).is_empty());
+    }
+
+    #[test]
+    fn test_range_matches_sorted_map() {
+        let trie = sample_trie();
+        let entries = sample_entries();
+        let bounds: [&[u8]; 7] = [b"", b"k", b"key", b"key1", b"key150", b"key42", b"kez"];
+
+        for start in bounds {
+            for end in bounds {
+                if start > end {
+                    assert!(collect_iter(trie.range(start..end)).is_empty());
+                    continue;
+                }
+
+                let expected: Vec<_> = entries
+                    .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                assert_eq!(collect_iter(trie.range(start..end)), expected, "{:?}..{:?}", start, end);
+
+                if start < end {
+                    let expected: Vec<_> = entries
+                        .range::<[u8], _>((Bound::Excluded(start), Bound::Included(end)))
+                        .map(|(key, value)| (key.clone(), value.clone()))
+                        .collect();
+                    let iter = trie.range::<&[u8], _>((Bound::Excluded(start), Bound::Included(end)));
+                    assert_eq!(collect_iter(iter), expected, "({:?}, {:?}]", start, end);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_unbounded_ends() {
+        let trie = sample_trie();
+        let entries = sample_entries();
+
+        let expected: Vec<_> = entries.range(b"key5".to_vec()..).map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(collect_iter(trie.range(&b"key5"[..]..)), expected);
+
+        let expected: Vec<_> = entries.range(..=b"key5".to_vec()).map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(collect_iter(trie.range(..=&b"key5"[..])), expected);
+    }
+
+    #[test]
+    fn test_iter_prefix_only_loads_matching_subtree() {
+        let mut trie = sample_trie();
+        let root = trie.commit().unwrap();
+        let counting = CountingDb::new(trie.into_db());
+        let trie = EthTrie::from_root(root, counting);
+
+        trie.get(b"key123").unwrap().unwrap();
+        let lookup_reads = trie.db().reads.replace(0);
+
+        assert_eq!(collect_iter(trie.iter_prefix(b"key123")).len(), 1);
+        assert_eq!(trie.db().reads.replace(0), lookup_reads);
+
+        assert_eq!(collect_iter(trie.range(&b"key123"[..]..&b"key124"[..])).len(), 1);
+        assert_eq!(trie.db().reads.replace(0), lookup_reads);
+
+        assert_eq!(collect_iter(trie.iter()).len(), sample_entries().len());
+        assert!(trie.db().reads.get() > 5 * lookup_reads);
+    }
+
+    #[test]
+    fn test_range_reports_missing_node() {
+        let trie = EthTrie::from_root([0x11; 32], MemoryDb::new());
+        let mut iter = trie.range(&b"a"[..]..&b"b"[..]);
+        assert!(matches!(iter.next(), Some(Err(TrieError::MissingNode(_)))));
+        assert!(iter.next().is_none());
+    }
+}
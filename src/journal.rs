@@ -0,0 +1,276 @@
+use crate::error::TrieError;
+
+/// identifies a checkpoint taken with `EthTrie::checkpoint`. ids are never
+/// reused, so a stale id is reported rather than matching a later checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CheckpointId(u64);
+
+/// a key and the value it had before a write, `None` if it was absent.
+pub(crate) type UndoEntry = (Vec<u8>, Option<Vec<u8>>);
+
+/// undo log for nested checkpoints: the prior value of every key written
+/// since the oldest open checkpoint, and where each checkpoint starts in it.
+#[derive(Debug, Default)]
+pub(crate) struct Journal {
+    entries: Vec<UndoEntry>,
+    checkpoints: Vec<(CheckpointId, usize)>,
+    next_id: u64,
+}
+
+impl Journal {
+    pub(crate) fn checkpoint(&mut self) -> CheckpointId {
+        let id = CheckpointId(self.next_id);
+        self.next_id += 1;
+        self.checkpoints.push((id, self.entries.len()));
+        id
+    }
+
+    /// whether writes need recording, i.e. some checkpoint is open.
+    pub(crate) fn is_active(&self) -> bool {
+        !self.checkpoints.is_empty()
+    }
+
+    /// the number of undo entries recorded across open checkpoints.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn record(&mut self, key: &[u8], previous: Option<Vec<u8>>) {
+        self.entries.push((key.to_vec(), previous));
+    }
+
+    /// where the writes made since `id` start in the log.
+    pub(crate) fn start_of(&self, id: CheckpointId) -> Result<usize, TrieError> {
+        self.find(id).map(|(_, len)| len)
+    }
+
+    /// takes the most recent write, if it was made at or after `start`.
+    pub(crate) fn pop_from(&mut self, start: usize) -> Option<UndoEntry> {
+        if self.entries.len() > start {
+            self.entries.pop()
+        } else {
+            None
+        }
+    }
+
+    /// closes `id` and every checkpoint taken after it, once their writes
+    /// have been undone.
+    pub(crate) fn close(&mut self, id: CheckpointId) -> Result<(), TrieError> {
+        let (start, _) = self.find(id)?;
+        self.checkpoints.truncate(start);
+        Ok(())
+    }
+
+    /// closes `id` and every checkpoint taken after it, keeping their
+    /// writes. the entries are still needed while an outer checkpoint is
+    /// open, and dropped once none is.
+    pub(crate) fn discard(&mut self, id: CheckpointId) -> Result<(), TrieError> {
+        let (start, _) = self.find(id)?;
+        self.checkpoints.truncate(start);
+        if self.checkpoints.is_empty() {
+            self.entries.clear();
+        }
+        Ok(())
+    }
+
+    fn find(&self, id: CheckpointId) -> Result<(usize, usize), TrieError> {
+        self.checkpoints
+            .iter()
+            .position(|(open, _)| *open == id)
+            .map(|index| (index, self.checkpoints[index].1))
+            .ok_or(TrieError::UnknownCheckpoint(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::db::{MemoryDb, NodeDb};
+    use crate::trie::{hashed_key, hashed_trie, EthTrie, EMPTY_ROOT};
+
+    #[test]
+    fn test_revert_restores_root_and_values() {
+        let mut trie = hashed_trie(200);
+        let root = trie.root_hash();
+
+        let checkpoint = trie.checkpoint();
+        trie.insert(&hashed_key(1), b"updated").unwrap();
+        trie.insert(&hashed_key(1), b"updated twice").unwrap();
+        trie.insert(&hashed_key(500), b"new").unwrap();
+        trie.remove(&hashed_key(2)).unwrap();
+        trie.remove(&hashed_key(600)).unwrap();
+        trie.insert(b"do", b"verb").unwrap();
+        trie.remove(b"do").unwrap();
+        assert_ne!(trie.root_hash(), root);
+
+        trie.revert_to(checkpoint).unwrap();
+        assert_eq!(trie.root_hash(), root);
+        assert_eq!(trie.get(&hashed_key(1)).unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(trie.get(&hashed_key(2)).unwrap(), Some(b"value-2".to_vec()));
+        assert_eq!(trie.get(&hashed_key(500)).unwrap(), None);
+        assert!(!trie_is_journaling(&trie));
+    }
+
+    #[test]
+    fn test_nested_checkpoints() {
+        let mut trie = hashed_trie(200);
+        let root0 = trie.root_hash();
+
+        let outer = trie.checkpoint();
+        trie.insert(&hashed_key(300), b"a").unwrap();
+        let root1 = trie.root_hash();
+
+        let middle = trie.checkpoint();
+        trie.remove(&hashed_key(5)).unwrap();
+        let root2 = trie.root_hash();
+
+        let inner = trie.checkpoint();
+        trie.insert(&hashed_key(5), b"back with another value").unwrap();
+        trie.insert(&hashed_key(301), b"b").unwrap();
+
+        trie.revert_to(inner).unwrap();
+        assert_eq!(trie.root_hash(), root2);
+
+        // discarding keeps the writes, which the outer checkpoint still undoes.
+        trie.insert(&hashed_key(302), b"c").unwrap();
+        trie.discard(middle).unwrap();
+        assert_ne!(trie.root_hash(), root1);
+        assert_eq!(trie.get(&hashed_key(302)).unwrap(), Some(b"c".to_vec()));
+
+        trie.revert_to(outer).unwrap();
+        assert_eq!(trie.root_hash(), root0);
+    }
+
+    #[test]
+    fn test_reverting_outer_checkpoint_closes_inner_ones() {
+        let mut trie = hashed_trie(200);
+        let root = trie.root_hash();
+
+        let outer = trie.checkpoint();
+        trie.insert(&hashed_key(400), b"x").unwrap();
+        let inner = trie.checkpoint();
+        trie.insert(&hashed_key(401), b"y").unwrap();
+
+        trie.revert_to(outer).unwrap();
+        assert_eq!(trie.root_hash(), root);
+        assert!(matches!(trie.revert_to(inner), Err(TrieError::UnknownCheckpoint(id)) if id == inner));
+        assert!(matches!(trie.discard(outer), Err(TrieError::UnknownCheckpoint(_))));
+
+        // a new checkpoint never reuses a closed id.
+        assert_ne!(trie.checkpoint(), outer);
+    }
+
+    #[test]
+    fn test_deeply_nested_checkpoints_unwind_one_by_one() {
+        let mut trie = EthTrie::new();
+        let mut roots = Vec::new();
+        let mut checkpoints = Vec::new();
+        for i in 0..100 {
+            roots.push(trie.root_hash());
+            checkpoints.push(trie.checkpoint());
+            trie.insert(&hashed_key(i % 10), format!("write-{}", i).as_bytes()).unwrap();
+            if i % 3 == 0 {
+                trie.remove(&hashed_key((i + 5) % 10)).unwrap();
+            }
+        }
+
+        while let Some(checkpoint) = checkpoints.pop() {
+            trie.revert_to(checkpoint).unwrap();
+            assert_eq!(trie.root_hash(), roots.pop().unwrap());
+        }
+        assert_eq!(trie.root_hash(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_revert_after_commit() {
+        let mut trie = hashed_trie(200);
+        let root = trie.commit().unwrap();
+
+        let checkpoint = trie.checkpoint();
+        trie.insert(&hashed_key(7), b"changed").unwrap();
+        trie.remove(&hashed_key(8)).unwrap();
+        trie.commit().unwrap();
+
+        trie.revert_to(checkpoint).unwrap();
+        assert_eq!(trie.root_hash(), root);
+        assert_eq!(trie.commit().unwrap(), root);
+    }
+
+    #[test]
+    fn test_journal_records_only_written_keys() {
+        let mut trie = hashed_trie(200);
+        assert!(!trie_is_journaling(&trie));
+
+        let checkpoint = trie.checkpoint();
+        trie.insert(&hashed_key(1), b"x").unwrap();
+        trie.remove(&hashed_key(2)).unwrap();
+        trie.remove(&hashed_key(999)).unwrap();
+        assert_eq!(trie.journal().len(), 2);
+
+        trie.discard(checkpoint).unwrap();
+        assert_eq!(trie.journal().len(), 0);
+        trie.insert(&hashed_key(3), b"y").unwrap();
+        assert_eq!(trie.journal().len(), 0);
+    }
+
+    /// serves `reads_left` more reads, then reports every node missing.
+    struct FlakyDb {
+        inner: MemoryDb,
+        reads_left: Cell<usize>,
+    }
+
+    impl NodeDb for FlakyDb {
+        fn get(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, TrieError> {
+            match self.reads_left.get() {
+                0 => Ok(None),
+                left => {
+                    self.reads_left.set(left - 1);
+                    self.inner.get(hash)
+                }
+            }
+        }
+
+        fn put(&mut self, hash: [u8; 32], data: Vec<u8>) -> Result<(), TrieError> {
+            self.inner.put(hash, data)
+        }
+
+        fn remove(&mut self, hash: &[u8; 32]) -> Result<(), TrieError> {
+            self.inner.remove(hash)
+        }
+    }
+
+    #[test]
+    fn test_failed_revert_can_be_retried() {
+        let mut base = hashed_trie(200);
+        let root = base.commit().unwrap();
+        let db = FlakyDb { inner: base.into_db(), reads_left: Cell::new(usize::MAX) };
+        let mut trie = EthTrie::from_root(root, db);
+
+        let checkpoint = trie.checkpoint();
+        for i in 0..20 {
+            trie.insert(&hashed_key(i), b"changed").unwrap();
+        }
+        trie.remove(&hashed_key(50)).unwrap();
+        // after a commit, undoing the writes has to load nodes again.
+        let changed = trie.commit().unwrap();
+
+        trie.db().reads_left.set(10);
+        assert!(matches!(trie.revert_to(checkpoint), Err(TrieError::MissingNode(_))));
+        let partial = trie.root_hash();
+        assert!(partial != changed && partial != root, "the revert should stop partway");
+        assert!(trie.journal().is_active());
+
+        trie.db().reads_left.set(usize::MAX);
+        trie.revert_to(checkpoint).unwrap();
+        assert_eq!(trie.root_hash(), root);
+        assert_eq!(trie.get(&hashed_key(50)).unwrap(), Some(b"value-50".to_vec()));
+        assert!(!trie.journal().is_active());
+    }
+
+    fn trie_is_journaling(trie: &EthTrie) -> bool {
+        trie.journal().is_active()
+    }
+}
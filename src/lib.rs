@@ -1,3 +1,15 @@
+pub mod account_proof;
+pub mod builder;
+pub mod db;
+pub mod diff;
+pub mod error;
+pub mod iter;
+pub mod journal;
 pub mod nibbles;
 pub mod node;
+pub mod ordered;
+pub mod range;
+pub mod secure;
+pub mod state;
 pub mod trie;
+pub mod witness;
@@ -1,14 +1,19 @@
 use clap::{Parser, Subcommand};
+use merkle_trie_rs::db::FileDb;
+use merkle_trie_rs::error::TrieError;
 use merkle_trie_rs::trie::EthTrie;
 use std::fs;
-use std::path::Path;
-
-const STATE_FILE: &str = "trie.json";
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 #[derive(Parser, Debug)]
 #[command(name = "merkle-trie-rs")]
 #[command(about = "a merkle patricia trie implementation in rust", long_about = None)]
 struct Args {
+    /// path of the on-disk node store
+    #[arg(long, global = true, default_value = "trie.db")]
+    db: PathBuf,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -18,6 +23,9 @@ enum Commands {
     Insert {
         key: String,
         value: String,
+        /// print the whole trie afterwards, which loads every node
+        #[arg(long)]
+        tree: bool,
     },
     Get {
         key: String,
@@ -29,42 +37,46 @@ enum Commands {
     Clear,
 }
 
-fn load_trie() -> EthTrie {
-    if Path::new(STATE_FILE).exists() {
-        let data = fs::read_to_string(STATE_FILE)
-            .expect("failed to read trie state file");
-        serde_json::from_str(&data)
-            .expect("failed to deserialize trie")
-    } else {
-        EthTrie::new()
-    }
+fn load_trie(path: &Path) -> Result<EthTrie<FileDb>, TrieError> {
+    let db = FileDb::open(path)?;
+    Ok(EthTrie::from_root(db.root(), db))
 }
 
-fn save_trie(trie: &EthTrie) {
-    let data = serde_json::to_string(trie)
-        .expect("failed to serialize trie");
-    fs::write(STATE_FILE, data)
-        .expect("failed to write trie state file");
+fn save_trie(trie: &mut EthTrie<FileDb>) -> Result<(), TrieError> {
+    let root = trie.commit()?;
+    trie.db_mut().set_root(root)
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();
 
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), TrieError> {
     match args.command {
-        Commands::Insert { key, value } => {
-            let mut trie = load_trie();
-            trie.insert(key.as_bytes(), value.as_bytes());
-            save_trie(&trie);
+        Commands::Insert { key, value, tree } => {
+            let mut trie = load_trie(&args.db)?;
+            trie.insert(key.as_bytes(), value.as_bytes())?;
+            save_trie(&mut trie)?;
             
             println!("inserted: '{}' => '{}'", key, value);
             println!("root hash: {}", hex::encode(trie.root_hash()));
-            println!();
-            trie.print_tree();
+            if tree {
+                println!();
+                trie.print_tree();
+            }
         }
         Commands::Get { key } => {
-            let trie = load_trie();
+            let trie = load_trie(&args.db)?;
             
-            match trie.get(key.as_bytes()) {
+            match trie.get(key.as_bytes())? {
                 Some(value) => {
                     println!("found: '{}' => '{}'", key, String::from_utf8_lossy(&value));
                 }
@@ -74,9 +86,9 @@ fn main() {
             }
         }
         Commands::Proof { key } => {
-            let trie = load_trie();
+            let trie = load_trie(&args.db)?;
             
-            let proof = trie.get_proof(key.as_bytes());
+            let proof = trie.get_proof(key.as_bytes())?;
             let root_hash = trie.root_hash();
             
             println!("generating proof for key: '{}'", key);
@@ -92,22 +104,26 @@ fn main() {
             
             println!();
             match EthTrie::verify_proof(&root_hash, key.as_bytes(), &proof) {
-                Some(value) => {
+                Ok(Some(value)) => {
                     println!("proof verified successfully");
                     println!("  value: '{}'", String::from_utf8_lossy(&value));
                 }
-                None => {
+                Ok(None) => {
+                    println!("proof verified successfully");
+                    println!("  key '{}' is not in the trie", key);
+                }
+                Err(err) => {
                     println!("proof verification failed");
+                    return Err(err.into());
                 }
             }
         }
         Commands::Clear => {
-            if Path::new(STATE_FILE).exists() {
-                fs::remove_file(STATE_FILE)
-                    .expect("failed to remove state file");
+            if args.db.exists() {
+                fs::remove_file(&args.db)?;
                 println!("trie state cleared");
             } else {
-                println!("no state file to clear");
+                println!("no node store to clear");
             }
         }
         Commands::Demo => {
@@ -124,7 +140,7 @@ fn main() {
             ];
             
             for (key, value) in &entries {
-                trie.insert(key.as_bytes(), value.as_bytes());
+                trie.insert(key.as_bytes(), value.as_bytes())?;
                 println!("   inserted: '{}' => '{}'", key, value);
             }
             
@@ -134,14 +150,13 @@ fn main() {
             
             println!("\n3. retrieving values...");
             for (key, expected_value) in &entries {
-                match trie.get(key.as_bytes()) {
-                    Some(value) => {
-                        println!("   get('{}') => '{}'", key, String::from_utf8_lossy(&value));
-                        assert_eq!(value, expected_value.as_bytes());
-                    }
-                    None => {
-                        println!("   get('{}') => not found ", key);
-                    }
+                let value = trie.get(key.as_bytes())?;
+                match &value {
+                    Some(value) => println!("   get('{}') => '{}'", key, String::from_utf8_lossy(value)),
+                    None => println!("   get('{}') => not found", key),
+                }
+                if value.as_deref() != Some(expected_value.as_bytes()) {
+                    return Err(TrieError::ValueMismatch(key.as_bytes().to_vec()));
                 }
             }
             
@@ -149,9 +164,9 @@ fn main() {
             let root_hash = trie.root_hash();
             
             for (key, _) in &entries {
-                let proof = trie.get_proof(key.as_bytes());
+                let proof = trie.get_proof(key.as_bytes())?;
                 match EthTrie::verify_proof(&root_hash, key.as_bytes(), &proof) {
-                    Some(value) => {
+                    Ok(Some(value)) => {
                         println!(
                             "   proof for '{}': {} nodes, verified (value: '{}')",
                             key,
@@ -159,15 +174,18 @@ fn main() {
                             String::from_utf8_lossy(&value)
                         );
                     }
-                    None => {
-                        println!("   proof for '{}': verification failed", key);
+                    Ok(None) => {
+                        println!("   proof for '{}': verified absent", key);
+                    }
+                    Err(err) => {
+                        println!("   proof for '{}': verification failed ({})", key, err);
                     }
                 }
             }
             
             println!("\n5. testing non-existent key...");
             let missing_key = "cat";
-            match trie.get(missing_key.as_bytes()) {
+            match trie.get(missing_key.as_bytes())? {
                 Some(_) => println!("   get('{}') => found (unexpected)", missing_key),
                 None => println!("   get('{}') => not found", missing_key),
             }
@@ -175,5 +193,7 @@ fn main() {
             println!("\n=== demo complete ===");
         }
     }
+
+    Ok(())
 }
 
@@ -75,6 +75,37 @@ pub fn encode_compact(nibbles: &[u8], is_leaf: bool) -> Vec<u8> {
     result
 }
 
+pub fn decode_compact(compact: &[u8]) -> (Vec<u8>, bool) {
+    if compact.is_empty() {
+        return (Vec::new(), false);
+    }
+
+    let first_byte = compact[0];
+    let flag = first_byte >> 4;
+    let is_leaf = (flag & 0x2) != 0;
+    let mut nibbles = Vec::with_capacity(compact.len() * 2);
+
+    if (flag & 0x1) != 0 {
+        nibbles.push(first_byte & 0x0F);
+    }
+
+    for &byte in &compact[1..] {
+        nibbles.push(byte >> 4);
+        nibbles.push(byte & 0x0F);
+    }
+
+    (nibbles, is_leaf)
+}
+
+/// packs a nibble path back into bytes. an odd trailing nibble is padded
+/// with a zero low nibble.
+pub fn nibbles_to_bytes(nibbles: &[u8]) -> Vec<u8> {
+    nibbles
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -320,4 +351,44 @@ mod tests {
         let result = encode_compact(&nibbles, false);
         assert_eq!(result, vec![0x00, 0xA5, 0xA5]);
     }
+
+    #[test]
+    fn test_decode_compact_leaf_odd_length() {
+        assert_eq!(decode_compact(&[0x3A, 0xBC]), (vec![0xA, 0xB, 0xC], true));
+    }
+
+    #[test]
+    fn test_decode_compact_extension_even_length() {
+        assert_eq!(decode_compact(&[0x00, 0xAB]), (vec![0xA, 0xB], false));
+    }
+
+    #[test]
+    fn test_decode_compact_empty_paths() {
+        assert_eq!(decode_compact(&[0x20]), (vec![], true));
+        assert_eq!(decode_compact(&[0x00]), (vec![], false));
+    }
+
+    #[test]
+    fn test_decode_compact_round_trip() {
+        let paths: &[&[u8]] = &[&[], &[0x5], &[0x1, 0x2], &[0xF, 0x0, 0xA], &[0x0, 0x0, 0xF, 0xF]];
+        for path in paths {
+            for is_leaf in [true, false] {
+                let encoded = encode_compact(path, is_leaf);
+                assert_eq!(decode_compact(&encoded), (path.to_vec(), is_leaf));
+            }
+        }
+    }
+
+    #[test]
+    fn test_nibbles_to_bytes_round_trip() {
+        let data = [0x00, 0x12, 0xAB, 0xFF];
+        let nibbles = Nibbles::from_raw(&data, false);
+        assert_eq!(nibbles_to_bytes(nibbles.as_slice()), data.to_vec());
+        assert_eq!(nibbles_to_bytes(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_nibbles_to_bytes_odd_length() {
+        assert_eq!(nibbles_to_bytes(&[0x1, 0x2, 0x3]), vec![0x12, 0x30]);
+    }
 }
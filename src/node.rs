@@ -1,60 +1,179 @@
+use std::sync::OnceLock;
+
 use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
 use tiny_keccak::{Hasher, Keccak};
-use serde::{Deserialize, Serialize};
 
-use crate::nibbles::encode_compact;
+use crate::nibbles::{decode_compact, encode_compact};
+
+/// how many dirty nodes a subtree needs before `hash_in_parallel` gives it
+/// its own task. hashing a node takes around a microsecond, so smaller
+/// subtrees would spend more on scheduling than they save.
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_NODES: usize = 256;
+
+/// the reference of a leaf, extension or branch, computed the first time
+/// the node is hashed. a node whose cache is empty is dirty.
+#[derive(Clone, Debug, Default)]
+pub struct NodeCache(OnceLock<Vec<u8>>);
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default)]
 pub enum Node {
+    #[default]
     Null,
     Leaf {
         key: Vec<u8>,
         value: Vec<u8>,
+        cache: NodeCache,
     },
     Extension {
         prefix: Vec<u8>,
         next: Box<Node>,
+        cache: NodeCache,
     },
     Branch {
         children: [Box<Node>; 16],
         value: Option<Vec<u8>>,
+        cache: NodeCache,
     },
+    /// a child reference that was only available as its keccak hash, e.g. a
+    /// node decoded from rlp whose subtree has not been resolved.
+    Hash([u8; 32]),
+}
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    let mut output = [0u8; 32];
+    hasher.update(data);
+    hasher.finalize(&mut output);
+    output
 }
 
 impl Node {
-    fn hash_or_raw(node: &Node) -> Vec<u8> {
-        let encoded = rlp::encode(node);
-        
-        if encoded.len() < 32 {
-            encoded.to_vec()
-        } else {
-            let mut hasher = Keccak::v256();
-            let mut output = [0u8; 32];
-            hasher.update(&encoded);
-            hasher.finalize(&mut output);
-            output.to_vec()
+    pub fn leaf(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Node::Leaf { key, value, cache: NodeCache::default() }
+    }
+
+    pub fn extension(prefix: Vec<u8>, next: Box<Node>) -> Self {
+        Node::Extension { prefix, next, cache: NodeCache::default() }
+    }
+
+    pub fn branch(children: [Box<Node>; 16], value: Option<Vec<u8>>) -> Self {
+        Node::Branch { children, value, cache: NodeCache::default() }
+    }
+
+    /// the reference a parent stores for this node: its keccak hash, or its
+    /// own rlp when that encodes to fewer than 32 bytes. the result is cached
+    /// on the node until it is marked dirty, so rehashing a trie only
+    /// re-encodes the nodes that changed.
+    pub fn reference(&self) -> &[u8] {
+        match self {
+            Node::Null => &[0x80],
+            Node::Hash(hash) => hash,
+            Node::Leaf { cache, .. } | Node::Extension { cache, .. } | Node::Branch { cache, .. } => {
+                cache.0.get_or_init(|| {
+                    let encoded = rlp::encode(self);
+                    if encoded.len() < 32 {
+                        encoded.to_vec()
+                    } else {
+                        keccak256(&encoded).to_vec()
+                    }
+                })
+            }
         }
     }
 
-    fn decode_compact(compact: &[u8]) -> Vec<u8> {
-        if compact.is_empty() {
-            return Vec::new();
+    /// drops the cached reference after the node was modified in place.
+    pub(crate) fn mark_dirty(&mut self) {
+        if let Node::Leaf { cache, .. } | Node::Extension { cache, .. } | Node::Branch { cache, .. } = self {
+            *cache = NodeCache::default();
         }
+    }
 
-        let first_byte = compact[0];
-        let prefix = first_byte >> 4;
-        let mut nibbles = Vec::new();
+    /// whether the node's reference is still cached from an earlier hash.
+    #[cfg(any(test, feature = "parallel"))]
+    pub(crate) fn is_cached(&self) -> bool {
+        match self {
+            Node::Null | Node::Hash(_) => true,
+            Node::Leaf { cache, .. } | Node::Extension { cache, .. } | Node::Branch { cache, .. } => {
+                cache.0.get().is_some()
+            }
+        }
+    }
+
+    /// fills the reference caches below the top `levels` branches on the
+    /// rayon pool, one task per child subtree with at least
+    /// `PARALLEL_MIN_NODES` dirty nodes, so a following `reference` only
+    /// encodes those branches itself. smaller subtrees are left to be hashed
+    /// inline, and a branch with a single large subtree is descended without
+    /// spawning.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn hash_in_parallel(&self, levels: usize) {
+        use rayon::prelude::*;
+
+        if levels == 0 || self.is_cached() {
+            return;
+        }
+        match self {
+            Node::Extension { next, .. } => next.hash_in_parallel(levels),
+            Node::Branch { children, .. } => {
+                let large: Vec<&Node> = children
+                    .iter()
+                    .map(|child| &**child)
+                    .filter(|child| child.count_dirty(PARALLEL_MIN_NODES) >= PARALLEL_MIN_NODES)
+                    .collect();
+                match large.as_slice() {
+                    [] => {}
+                    [child] => child.hash_in_parallel(levels),
+                    _ => large.par_iter().for_each(|child| {
+                        child.hash_in_parallel(levels - 1);
+                        child.reference();
+                    }),
+                }
+            }
+            _ => {}
+        }
+    }
 
-        if (prefix & 0x1) != 0 {
-            nibbles.push(first_byte & 0x0F);
+    /// the number of nodes in this subtree whose reference is not cached,
+    /// counting no further than `limit`.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn count_dirty(&self, limit: usize) -> usize {
+        if limit == 0 || self.is_cached() {
+            return 0;
+        }
+        match self {
+            Node::Extension { next, .. } => 1 + next.count_dirty(limit - 1),
+            Node::Branch { children, .. } => {
+                let mut count = 1;
+                for child in children.iter() {
+                    if count >= limit {
+                        break;
+                    }
+                    count += child.count_dirty(limit - count);
+                }
+                count
+            }
+            _ => 1,
         }
+    }
 
-        for &byte in &compact[1..] {
-            nibbles.push(byte >> 4);
-            nibbles.push(byte & 0x0F);
+    /// records `hash` as the reference of a node that was just decoded from
+    /// its hashed encoding, so it does not have to be hashed again.
+    pub(crate) fn set_hash(&self, hash: [u8; 32]) {
+        if let Node::Leaf { cache, .. } | Node::Extension { cache, .. } | Node::Branch { cache, .. } = self {
+            let _ = cache.0.set(hash.to_vec());
         }
+    }
 
-        nibbles
+    /// appends a child reference: a 32-byte hash as a string, or the
+    /// child's own rlp inline when it encodes to fewer than 32 bytes.
+    fn append_reference(s: &mut RlpStream, node: &Node) {
+        let data = node.reference();
+        if data.len() == 32 {
+            s.append(&data);
+        } else {
+            s.append_raw(data, 1);
+        }
     }
 }
 
@@ -64,25 +183,23 @@ impl Encodable for Node {
             Node::Null => {
                 s.append_empty_data();
             }
-            Node::Leaf { key, value } => {
+            Node::Leaf { key, value, .. } => {
                 s.begin_list(2);
                 let encoded_path = encode_compact(key, true);
                 s.append(&encoded_path);
                 s.append(value);
             }
-            Node::Extension { prefix, next } => {
+            Node::Extension { prefix, next, .. } => {
                 s.begin_list(2);
                 let encoded_path = encode_compact(prefix, false);
                 s.append(&encoded_path);
-                let next_data = Node::hash_or_raw(next);
-                s.append(&next_data);
+                Node::append_reference(s, next);
             }
-            Node::Branch { children, value } => {
+            Node::Branch { children, value, .. } => {
                 s.begin_list(17);
                 
                 for child in children.iter() {
-                    let child_data = Node::hash_or_raw(child);
-                    s.append(&child_data);
+                    Node::append_reference(s, child);
                 }
                 
                 match value {
@@ -90,6 +207,9 @@ impl Encodable for Node {
                     None => s.append_empty_data(),
                 };
             }
+            Node::Hash(hash) => {
+                s.append(&hash.as_slice());
+            }
         }
     }
 }
@@ -100,8 +220,14 @@ impl Decodable for Node {
             return Ok(Node::Null);
         }
 
-        if !rlp.is_list() {
-            return Err(DecoderError::RlpExpectedToBeList);
+        if rlp.is_data() {
+            let data = rlp.data()?;
+            if data.len() != 32 {
+                return Err(DecoderError::Custom("invalid node reference"));
+            }
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(data);
+            return Ok(Node::Hash(hash));
         }
 
         let item_count = rlp.item_count()?;
@@ -116,28 +242,28 @@ impl Decodable for Node {
 
                 let first_byte = path[0];
                 let prefix = first_byte >> 4;
+                if prefix > 3 {
+                    return Err(DecoderError::Custom("invalid path flag"));
+                }
+                // an even-length path pads the flag byte with a zero nibble.
+                if prefix & 0x1 == 0 && first_byte & 0x0F != 0 {
+                    return Err(DecoderError::Custom("invalid path padding"));
+                }
                 
                 let is_leaf = (prefix & 0x2) != 0;
                 
                 if is_leaf {
                     let value: Vec<u8> = rlp.val_at(1)?;
                     
-                    let key = Self::decode_compact(&path);
+                    let (key, _) = decode_compact(&path);
                     
-                    Ok(Node::Leaf { key, value })
+                    Ok(Node::leaf(key, value))
                 } else {
-                    let next_data: Vec<u8> = rlp.val_at(1)?;
+                    let (prefix, _) = decode_compact(&path);
                     
-                    let prefix = Self::decode_compact(&path);
+                    let next = Box::new(Node::decode(&rlp.at(1)?)?);
                     
-                    let next = if next_data.len() == 32 {
-                        Box::new(Node::Null)
-                    } else {
-                        let next_rlp = Rlp::new(&next_data);
-                        Box::new(Node::decode(&next_rlp)?)
-                    };
-                    
-                    Ok(Node::Extension { prefix, next })
+                    Ok(Node::extension(prefix, next))
                 }
             }
             17 => {
@@ -148,16 +274,8 @@ impl Decodable for Node {
                     Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
                 ];
 
-                for i in 0..16 {
-                    let child_data: Vec<u8> = rlp.val_at(i)?;
-                    if !child_data.is_empty() {
-                        if child_data.len() == 32 {
-                            children[i] = Box::new(Node::Null);
-                        } else {
-                            let child_rlp = Rlp::new(&child_data);
-                            children[i] = Box::new(Node::decode(&child_rlp)?);
-                        }
-                    }
+                for (i, child) in children.iter_mut().enumerate() {
+                    **child = Node::decode(&rlp.at(i)?)?;
                 }
 
                 let value_data: Vec<u8> = rlp.val_at(16)?;
@@ -167,7 +285,7 @@ impl Decodable for Node {
                     Some(value_data)
                 };
 
-                Ok(Node::Branch { children, value })
+                Ok(Node::branch(children, value))
             }
             _ => Err(DecoderError::RlpIncorrectListLen),
         }
@@ -175,6 +293,7 @@ impl Decodable for Node {
 }
 
 #[cfg(test)]
+#[allow(clippy::len_zero)]
 mod tests {
     use super::*;
 
@@ -187,10 +306,7 @@ mod tests {
 
     #[test]
     fn test_leaf_node_encoding() {
-        let node = Node::Leaf {
-            key: vec![0xA, 0xB, 0xC],
-            value: vec![0x01, 0x02, 0x03],
-        };
+        let node = Node::leaf(vec![0xA, 0xB, 0xC], vec![0x01, 0x02, 0x03]);
         let encoded = rlp::encode(&node);
         assert!(encoded.len() > 0);
         assert!(encoded[0] >= 0xc0);
@@ -199,10 +315,7 @@ mod tests {
     #[test]
     fn test_extension_node_encoding() {
         let next_node = Node::Null;
-        let node = Node::Extension {
-            prefix: vec![0x1, 0x2],
-            next: Box::new(next_node),
-        };
+        let node = Node::extension(vec![0x1, 0x2], Box::new(next_node));
         let encoded = rlp::encode(&node);
         assert!(encoded.len() > 0);
         assert!(encoded[0] >= 0xc0);
@@ -216,10 +329,7 @@ mod tests {
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
         ];
-        let node = Node::Branch {
-            children,
-            value: None,
-        };
+        let node = Node::branch(children, None);
         let encoded = rlp::encode(&node);
         assert!(encoded.len() > 0);
     }
@@ -232,37 +342,120 @@ mod tests {
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
         ];
-        let node = Node::Branch {
-            children,
-            value: Some(vec![0xAA, 0xBB]),
-        };
+        let node = Node::branch(children, Some(vec![0xAA, 0xBB]));
         let encoded = rlp::encode(&node);
         assert!(encoded.len() > 0);
     }
 
     #[test]
-    fn test_hash_or_raw_small_node() {
+    fn test_reference_small_node() {
         let node = Node::Null;
-        let result = Node::hash_or_raw(&node);
+        let result = node.reference().to_vec();
         assert_eq!(result, vec![0x80]);
         assert!(result.len() < 32);
     }
 
     #[test]
-    fn test_hash_or_raw_large_node() {
+    fn test_reference_large_node() {
         let children: [Box<Node>; 16] = [
-            Box::new(Node::Leaf { key: vec![0x1, 0x2, 0x3, 0x4], value: vec![0xAA; 10] }),
+            Box::new(Node::leaf(vec![0x1, 0x2, 0x3, 0x4], vec![0xAA; 10])),
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
             Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
         ];
-        let node = Node::Branch {
-            children,
-            value: Some(vec![0xFF; 20]),
-        };
+        let node = Node::branch(children, Some(vec![0xFF; 20]));
         
-        let result = Node::hash_or_raw(&node);
+        let result = node.reference().to_vec();
         assert_eq!(result.len(), 32);
     }
+
+    #[test]
+    fn test_hash_node_round_trip() {
+        let node = Node::Hash([0xAB; 32]);
+        let encoded = rlp::encode(&node);
+        assert_eq!(encoded[0], 0xa0);
+
+        let decoded: Node = rlp::decode(&encoded).unwrap();
+        assert!(matches!(decoded, Node::Hash(hash) if hash == [0xAB; 32]));
+        assert_eq!(rlp::encode(&decoded), encoded);
+    }
+
+    #[test]
+    fn test_embedded_child_is_encoded_inline() {
+        let mut children: [Box<Node>; 16] = Default::default();
+        *children[3] = Node::leaf(vec![0x1], vec![0x2]);
+        let node = Node::branch(children, None);
+        let encoded = rlp::encode(&node);
+
+        let rlp = Rlp::new(&encoded);
+        assert!(rlp.at(3).unwrap().is_list(), "small children must be embedded as rlp lists");
+        assert_eq!(rlp.at(0).unwrap().as_raw(), &[0x80]);
+    }
+
+    #[test]
+    fn test_decode_preserves_hashed_children() {
+        let mut children: [Box<Node>; 16] = Default::default();
+        *children[0] = Node::leaf(vec![0x1, 0x2, 0x3, 0x4], vec![0xAA; 40]);
+        *children[7] = Node::leaf(vec![0x5], vec![0x01]);
+        let node = Node::branch(children, Some(vec![0xFF]));
+        let encoded = rlp::encode(&node);
+
+        let decoded: Node = rlp::decode(&encoded).unwrap();
+        match &decoded {
+            Node::Branch { children, value, .. } => {
+                assert!(matches!(*children[0], Node::Hash(_)));
+                assert!(matches!(*children[7], Node::Leaf { .. }));
+                assert!(matches!(*children[1], Node::Null));
+                assert_eq!(value, &Some(vec![0xFF]));
+            }
+            other => panic!("expected branch, got {:?}", other),
+        }
+        assert_eq!(rlp::encode(&decoded), encoded);
+    }
+
+    #[test]
+    fn test_decode_preserves_hashed_extension_target() {
+        let mut children: [Box<Node>; 16] = Default::default();
+        *children[0] = Node::leaf(vec![0x1], vec![0xAA; 40]);
+        *children[1] = Node::leaf(vec![0x2], vec![0xBB; 40]);
+        let node = Node::extension(vec![0x1, 0x2, 0x3], Box::new(Node::branch(children, None)));
+        let encoded = rlp::encode(&node);
+
+        let decoded: Node = rlp::decode(&encoded).unwrap();
+        assert!(matches!(&decoded, Node::Extension { next, .. } if matches!(**next, Node::Hash(_))));
+        assert_eq!(rlp::encode(&decoded), encoded);
+    }
+
+    #[test]
+    fn test_decode_rejects_short_reference() {
+        let result: Result<Node, _> = rlp::decode(&rlp::encode(&vec![0x01u8, 0x02, 0x03]));
+        assert!(result.is_err());
+    }
+
+    fn decode_leaf_with_path(path: &[u8]) -> Result<Node, DecoderError> {
+        let mut s = RlpStream::new_list(2);
+        s.append(&path);
+        s.append(&b"value".as_slice());
+        rlp::decode(&s.out())
+    }
+
+    #[test]
+    fn test_decode_rejects_invalid_path_flag() {
+        for flag in [0x4u8, 0x5, 0x9, 0xf] {
+            let result = decode_leaf_with_path(&[flag << 4, 0x12]);
+            assert_eq!(result.unwrap_err(), DecoderError::Custom("invalid path flag"));
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_nonzero_padding() {
+        // flags 0 and 2 mark even-length paths, whose padding nibble is zero.
+        for first in [0x01u8, 0x2f] {
+            let result = decode_leaf_with_path(&[first, 0x12]);
+            assert_eq!(result.unwrap_err(), DecoderError::Custom("invalid path padding"));
+        }
+        assert!(decode_leaf_with_path(&[0x20, 0x12]).is_ok());
+        assert!(decode_leaf_with_path(&[0x3f, 0x12]).is_ok());
+    }
 }
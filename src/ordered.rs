@@ -0,0 +1,103 @@
+use crate::error::ProofError;
+use crate::trie::EthTrie;
+
+/// the key an item is stored under in an ordered trie: `rlp(index)`.
+pub fn index_key(index: usize) -> Vec<u8> {
+    rlp::encode(&index).to_vec()
+}
+
+/// builds the trie that maps `rlp(index)` to each item, as block headers do
+/// for their transactions and receipts.
+pub fn ordered_trie<I>(items: I) -> EthTrie
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut trie = EthTrie::new();
+    for (index, item) in items.into_iter().enumerate() {
+        trie.insert(&index_key(index), item.as_ref())
+            .expect("an in-memory trie has no nodes to load");
+    }
+    trie
+}
+
+/// the root of the ordered trie over `items`, i.e. a header's
+/// `transactionsRoot` for encoded transactions or `receiptsRoot` for
+/// encoded receipts.
+pub fn ordered_trie_root<I>(items: I) -> [u8; 32]
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    ordered_trie(items).root_hash()
+}
+
+/// checks a proof from `ordered_trie(..).get_proof(&index_key(index))`
+/// against a header root and returns the item at `index`, if any.
+pub fn verify_ordered_proof(
+    root_hash: &[u8; 32],
+    index: usize,
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, ProofError> {
+    EthTrie::verify_proof(root_hash, &index_key(index), proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trie::EMPTY_ROOT;
+
+    #[test]
+    fn test_index_key_is_rlp_of_index() {
+        assert_eq!(index_key(0), vec![0x80]);
+        assert_eq!(index_key(1), vec![0x01]);
+        assert_eq!(index_key(0x7f), vec![0x7f]);
+        assert_eq!(index_key(0x80), vec![0x81, 0x80]);
+        assert_eq!(index_key(0x0400), vec![0x82, 0x04, 0x00]);
+    }
+
+    #[test]
+    fn test_ordered_trie_root_empty() {
+        assert_eq!(ordered_trie_root(Vec::<Vec<u8>>::new()), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_ordered_trie_root_known_vector() {
+        assert_eq!(
+            hex::encode(ordered_trie_root(["doe", "reindeer"])),
+            "e766d5d51b89dc39d981b41bda63248d7abce4f0225eefd023792a540bcffee3"
+        );
+    }
+
+    #[test]
+    fn test_ordered_trie_root_depends_on_order() {
+        assert_ne!(ordered_trie_root(["a", "b"]), ordered_trie_root(["b", "a"]));
+    }
+
+    #[test]
+    fn test_ordered_proofs_for_every_index() {
+        // enough items for the index keys to span one and two byte encodings.
+        let items: Vec<Vec<u8>> = (0..300u32).map(|i| format!("transaction-{}", i).into_bytes()).collect();
+        let trie = ordered_trie(&items);
+        let root = ordered_trie_root(&items);
+        assert_eq!(trie.root_hash(), root);
+
+        for (index, item) in items.iter().enumerate() {
+            let proof = trie.get_proof(&index_key(index)).unwrap();
+            assert_eq!(verify_ordered_proof(&root, index, &proof), Ok(Some(item.clone())));
+        }
+
+        let proof = trie.get_proof(&index_key(300)).unwrap();
+        assert_eq!(verify_ordered_proof(&root, 300, &proof), Ok(None));
+    }
+
+    #[test]
+    fn test_ordered_proof_rejects_wrong_index() {
+        let items = ["first", "second", "third"];
+        let trie = ordered_trie(items);
+        let root = trie.root_hash();
+
+        let proof = trie.get_proof(&index_key(1)).unwrap();
+        assert_ne!(verify_ordered_proof(&root, 2, &proof), Ok(Some(b"second".to_vec())));
+    }
+}
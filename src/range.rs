@@ -0,0 +1,333 @@
+use crate::db::NodeDb;
+use crate::error::{ProofError, RangeProofError, TrieError};
+use crate::nibbles::Nibbles;
+use crate::node::Node;
+use crate::trie::{EthTrie, ProofNodes, EMPTY_ROOT};
+
+/// a contiguous run of entries together with the nodes proving it, as
+/// served by snap sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeProof {
+    pub keys: Vec<Vec<u8>>,
+    pub values: Vec<Vec<u8>>,
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl<D: NodeDb> EthTrie<D> {
+    /// the first `limit` entries at or after `start`, with the nodes on the
+    /// paths to `start` and to the last returned key. at least one entry is
+    /// returned when any exists, since an empty range proves there are none.
+    pub fn prove_range(&self, start: &[u8], limit: usize) -> Result<RangeProof, TrieError> {
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for entry in self.range(start..).take(limit.max(1)) {
+            let (key, value) = entry?;
+            keys.push(key);
+            values.push(value);
+        }
+
+        let mut edges = vec![start.to_vec()];
+        edges.extend(keys.last().cloned());
+        let proof = self.get_multiproof(&edges)?;
+        Ok(RangeProof { keys, values, proof })
+    }
+}
+
+/// checks that `keys` and `values` are every entry of the trie with root
+/// `root` from `start` up to the last key, and returns whether the trie has
+/// more entries after it.
+///
+/// the edge proofs are loaded into a partial trie, everything between the
+/// edges is cut out and the given entries are inserted in its place; the
+/// result only hashes to `root` if no entry was left out, added or changed.
+/// with no keys, the proof must show that nothing follows `start`. with no
+/// proof, the entries must be the whole trie.
+pub fn verify_range_proof(
+    root: &[u8; 32],
+    start: &[u8],
+    keys: &[Vec<u8>],
+    values: &[Vec<u8>],
+    proof: &[Vec<u8>],
+) -> Result<bool, RangeProofError> {
+    if keys.len() != values.len() {
+        return Err(RangeProofError::LengthMismatch { keys: keys.len(), values: values.len() });
+    }
+    let ordered = keys.windows(2).all(|pair| pair[0] < pair[1]);
+    if !ordered || keys.first().is_some_and(|first| first.as_slice() < start) {
+        return Err(RangeProofError::UnorderedKeys);
+    }
+
+    let (partial, more) = if proof.is_empty() {
+        (Node::Null, false)
+    } else {
+        let bounds = Bounds {
+            left: Nibbles::from_raw(start, false).as_slice().to_vec(),
+            right: keys.last().map(|last| Nibbles::from_raw(last, false).as_slice().to_vec()),
+        };
+        let root_node = if *root == EMPTY_ROOT { Node::Null } else { Node::Hash(*root) };
+        let partial = prune(root_node, &[], &bounds, &EthTrie::proof_nodes(proof), 0)?;
+        let more = match &bounds.right {
+            Some(right) => has_right(&partial, &[], right),
+            None => false,
+        };
+        (partial, more)
+    };
+
+    let mut trie = EthTrie::from_node(partial);
+    for (key, value) in keys.iter().zip(values) {
+        // everything the entries are inserted into is either resolved from
+        // the proof or was cut out, so this only fails on a bad proof.
+        trie.insert(key, value).map_err(|_| RangeProofError::RootMismatch)?;
+    }
+    if trie.root_hash() != *root {
+        return Err(RangeProofError::RootMismatch);
+    }
+    Ok(more)
+}
+
+/// the nibble paths of the first and last key in the range. without a last
+/// key the range is unbounded.
+struct Bounds {
+    left: Vec<u8>,
+    right: Option<Vec<u8>>,
+}
+
+enum Span {
+    Outside,
+    Inside,
+    Overlap,
+}
+
+impl Bounds {
+    fn contains(&self, key: &[u8]) -> bool {
+        key >= self.left.as_slice() && self.right.as_ref().is_none_or(|right| key <= right.as_slice())
+    }
+
+    /// where the keys of a subtree at `path`, all of which start with
+    /// `path`, fall relative to the range.
+    fn locate(&self, path: &[u8]) -> Span {
+        let all_before = path < self.left.as_slice() && !self.left.starts_with(path);
+        let all_after = self.right.as_ref().is_some_and(|right| path > right.as_slice());
+        let all_from_left = path >= self.left.as_slice();
+        let all_to_right = self
+            .right
+            .as_ref()
+            .is_none_or(|right| path < right.as_slice() && !right.starts_with(path));
+
+        if all_before || all_after {
+            Span::Outside
+        } else if all_from_left && all_to_right {
+            Span::Inside
+        } else {
+            Span::Overlap
+        }
+    }
+}
+
+/// removes every entry inside `bounds` from the subtree at `path`. only
+/// subtrees straddling an edge of the range are resolved from the proof,
+/// and those all lie on the path to one of the edge keys.
+fn prune(node: Node, path: &[u8], bounds: &Bounds, nodes: &ProofNodes, depth: usize) -> Result<Node, ProofError> {
+    match bounds.locate(path) {
+        Span::Outside => return Ok(node),
+        Span::Inside => return Ok(Node::Null),
+        Span::Overlap => {}
+    }
+
+    let pruned = match node {
+        Node::Null => Node::Null,
+        Node::Hash(hash) => {
+            let resolved = EthTrie::proof_node(nodes, hash, depth)?;
+            return prune(resolved, path, bounds, nodes, depth);
+        }
+        Node::Leaf { key, value, .. } => {
+            if bounds.contains(&[path, &key].concat()) {
+                Node::Null
+            } else {
+                Node::leaf(key, value)
+            }
+        }
+        Node::Extension { prefix, next, .. } => {
+            match prune(*next, &[path, &prefix].concat(), bounds, nodes, depth + 1)? {
+                Node::Null => Node::Null,
+                next => Node::extension(prefix, Box::new(next)),
+            }
+        }
+        Node::Branch { mut children, value, .. } => {
+            for (nibble, child) in children.iter_mut().enumerate() {
+                let child_path = [path, &[nibble as u8]].concat();
+                **child = prune(std::mem::take(&mut **child), &child_path, bounds, nodes, depth + 1)?;
+            }
+            let value = value.filter(|_| !bounds.contains(path));
+            if value.is_none() && children.iter().all(|child| matches!(**child, Node::Null)) {
+                Node::Null
+            } else {
+                Node::branch(children, value)
+            }
+        }
+    };
+    Ok(pruned)
+}
+
+/// whether the pruned trie holds any key after `right`. subtrees left as
+/// hashes lie entirely on one side of the range, so their path decides.
+fn has_right(node: &Node, path: &[u8], right: &[u8]) -> bool {
+    match node {
+        Node::Null => false,
+        Node::Hash(_) => path > right,
+        Node::Leaf { key, .. } => [path, key.as_slice()].concat().as_slice() > right,
+        Node::Extension { prefix, next, .. } => has_right(next, &[path, prefix.as_slice()].concat(), right),
+        Node::Branch { children, value, .. } => {
+            (value.is_some() && path > right)
+                || children
+                    .iter()
+                    .enumerate()
+                    .any(|(nibble, child)| has_right(child, &[path, &[nibble as u8]].concat(), right))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trie::hashed_trie;
+
+    fn verify(root: &[u8; 32], start: &[u8], range: &RangeProof) -> Result<bool, RangeProofError> {
+        verify_range_proof(root, start, &range.keys, &range.values, &range.proof)
+    }
+
+    #[test]
+    fn test_pages_cover_the_whole_trie() {
+        let mut trie = hashed_trie(300);
+        let root = trie.commit().unwrap();
+        let expected: Vec<_> = trie.iter().collect::<Result<_, _>>().unwrap();
+
+        let mut seen = Vec::new();
+        let mut start = Vec::new();
+        loop {
+            let page = trie.prove_range(&start, 32).unwrap();
+            let more = verify(&root, &start, &page).unwrap();
+            seen.extend(page.keys.iter().cloned().zip(page.values.iter().cloned()));
+            if !more {
+                break;
+            }
+            // the next page starts right after the last key.
+            start = page.keys.last().unwrap().clone();
+            start.push(0);
+        }
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn test_range_from_absent_start_key() {
+        let trie = hashed_trie(100);
+        let root = trie.root_hash();
+        let start = [0x80; 32];
+        let page = trie.prove_range(&start, 10).unwrap();
+        assert_eq!(page.keys.len(), 10);
+        assert!(page.keys[0].as_slice() > start.as_slice());
+        assert_eq!(verify(&root, &start, &page), Ok(true));
+    }
+
+    #[test]
+    fn test_empty_range_after_last_key() {
+        let trie = hashed_trie(100);
+        let root = trie.root_hash();
+        let page = trie.prove_range(&[0xff; 33], 10).unwrap();
+        assert!(page.keys.is_empty());
+        assert!(!page.proof.is_empty());
+        assert_eq!(verify(&root, &[0xff; 33], &page), Ok(false));
+
+        // claiming nothing follows a key that does have successors fails.
+        let proof = trie.prove_range(&[0x80; 32], 10).unwrap().proof;
+        assert_eq!(
+            verify_range_proof(&root, &[0x80; 32], &[], &[], &proof),
+            Err(RangeProofError::RootMismatch)
+        );
+    }
+
+    #[test]
+    fn test_tampered_ranges_are_rejected() {
+        let trie = hashed_trie(200);
+        let root = trie.root_hash();
+        let start = [0x40; 32];
+        let page = trie.prove_range(&start, 20).unwrap();
+        assert_eq!(verify(&root, &start, &page), Ok(true));
+
+        let mut missing = page.clone();
+        missing.keys.remove(7);
+        missing.values.remove(7);
+        assert_eq!(verify(&root, &start, &missing), Err(RangeProofError::RootMismatch));
+
+        // dropping the first entry leaves a gap after `start`.
+        let mut gap = page.clone();
+        gap.keys.remove(0);
+        gap.values.remove(0);
+        assert_eq!(verify(&root, &start, &gap), Err(RangeProofError::RootMismatch));
+
+        let mut altered = page.clone();
+        altered.values[3] = b"forged".to_vec();
+        assert_eq!(verify(&root, &start, &altered), Err(RangeProofError::RootMismatch));
+
+        let mut extra = page.clone();
+        let mut key = extra.keys[4].clone();
+        key.push(0);
+        extra.keys.insert(5, key);
+        extra.values.insert(5, b"extra".to_vec());
+        assert_eq!(verify(&root, &start, &extra), Err(RangeProofError::RootMismatch));
+
+        let mut unordered = page.clone();
+        unordered.keys.swap(1, 2);
+        assert_eq!(verify(&root, &start, &unordered), Err(RangeProofError::UnorderedKeys));
+        assert_eq!(verify(&root, &[0xff; 32], &page), Err(RangeProofError::UnorderedKeys));
+
+        let mut short = page.clone();
+        short.values.pop();
+        assert_eq!(
+            verify(&root, &start, &short),
+            Err(RangeProofError::LengthMismatch { keys: 20, values: 19 })
+        );
+
+        let mut incomplete = page.clone();
+        incomplete.proof.pop();
+        assert!(matches!(
+            verify(&root, &start, &incomplete),
+            Err(RangeProofError::InvalidProof(ProofError::MissingNode { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_range_without_proof_is_the_whole_trie() {
+        let trie = hashed_trie(50);
+        let root = trie.root_hash();
+        let page = trie.prove_range(&[], 50).unwrap();
+        assert_eq!(verify_range_proof(&root, &[], &page.keys, &page.values, &[]), Ok(false));
+        assert_eq!(
+            verify_range_proof(&root, &[], &page.keys[..49], &page.values[..49], &[]),
+            Err(RangeProofError::RootMismatch)
+        );
+    }
+
+    #[test]
+    fn test_range_over_small_nodes_and_prefix_keys() {
+        let mut trie = EthTrie::new();
+        for (key, value) in [("a", "1"), ("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("dogs", "many"), ("z", "2")] {
+            trie.insert(key.as_bytes(), value.as_bytes()).unwrap();
+        }
+        let root = trie.root_hash();
+
+        for (start, limit) in [("", 2), ("b", 2), ("do", 1), ("dog", 2), ("doga", 3), ("e", 1), ("zz", 1)] {
+            let page = trie.prove_range(start.as_bytes(), limit).unwrap();
+            let expected_more = trie.range(start.as_bytes()..).nth(limit).is_some();
+            assert_eq!(verify(&root, start.as_bytes(), &page), Ok(expected_more), "start {:?}", start);
+        }
+    }
+
+    #[test]
+    fn test_range_of_empty_trie() {
+        let trie = EthTrie::new();
+        let page = trie.prove_range(b"a", 10).unwrap();
+        assert!(page.keys.is_empty() && page.proof.is_empty());
+        assert_eq!(verify(&EMPTY_ROOT, b"a", &page), Ok(false));
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use crate::db::{MemoryDb, NodeDb};
+use crate::error::{ProofError, TrieError};
+use crate::iter::TrieIter;
+use crate::node::keccak256;
+use crate::trie::EthTrie;
+
+/// a trie keyed by `keccak256(key)`, as ethereum's state and storage tries
+/// are. the same entries give the same root as geth's secure trie.
+///
+/// hashing discards the original keys, so iteration can only return them
+/// when preimages are being recorded (see `enable_preimages`).
+pub struct SecureTrie<D = MemoryDb> {
+    trie: EthTrie<D>,
+    preimages: Option<HashMap<[u8; 32], Vec<u8>>>,
+}
+
+impl SecureTrie {
+    pub fn new() -> Self {
+        SecureTrie::with_db(MemoryDb::new())
+    }
+
+    /// checks a proof produced by `SecureTrie::get_proof` for the unhashed
+    /// `key`. see `EthTrie::verify_proof`.
+    pub fn verify_proof(
+        root_hash: &[u8; 32],
+        key: &[u8],
+        proof: &[Vec<u8>],
+    ) -> Result<Option<Vec<u8>>, ProofError> {
+        EthTrie::verify_proof(root_hash, &keccak256(key), proof)
+    }
+}
+
+impl<D: NodeDb> SecureTrie<D> {
+    pub fn with_db(db: D) -> Self {
+        SecureTrie {
+            trie: EthTrie::with_db(db),
+            preimages: None,
+        }
+    }
+
+    /// opens the trie with the given root, loading nodes from `db` on demand.
+    pub fn from_root(root_hash: [u8; 32], db: D) -> Self {
+        SecureTrie {
+            trie: EthTrie::from_root(root_hash, db),
+            preimages: None,
+        }
+    }
+
+    /// starts remembering the original key of every entry inserted from now
+    /// on, so that `iter` can return it. preimages are kept in memory and
+    /// are not dropped when their key is removed.
+    pub fn enable_preimages(&mut self) {
+        self.preimages.get_or_insert_with(HashMap::new);
+    }
+
+    /// the original key whose hash is `hashed_key`, if it was recorded.
+    pub fn preimage(&self, hashed_key: &[u8; 32]) -> Option<&[u8]> {
+        self.preimages.as_ref()?.get(hashed_key).map(Vec::as_slice)
+    }
+
+    /// the underlying trie, keyed by the hashed keys.
+    pub fn trie(&self) -> &EthTrie<D> {
+        &self.trie
+    }
+
+    pub fn into_trie(self) -> EthTrie<D> {
+        self.trie
+    }
+
+    pub fn db(&self) -> &D {
+        self.trie.db()
+    }
+
+    pub fn db_mut(&mut self) -> &mut D {
+        self.trie.db_mut()
+    }
+
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.trie.root_hash()
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError> {
+        let hashed_key = keccak256(key);
+        self.trie.insert(&hashed_key, value)?;
+        if let Some(preimages) = &mut self.preimages {
+            preimages.insert(hashed_key, key.to_vec());
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.trie.remove(&keccak256(key))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.trie.get(&keccak256(key))
+    }
+
+    pub fn get_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.trie.get_proof(&keccak256(key))
+    }
+
+    pub fn commit(&mut self) -> Result<[u8; 32], TrieError> {
+        self.trie.commit()
+    }
+
+    /// iterates over `(key, value)` pairs with their original keys, in order
+    /// of the hashed keys. an entry whose preimage was never recorded yields
+    /// `TrieError::MissingPreimage` and ends the iteration.
+    pub fn iter(&self) -> SecureIter<'_, D> {
+        SecureIter {
+            inner: self.trie.iter(),
+            preimages: self.preimages.as_ref(),
+            failed: false,
+        }
+    }
+}
+
+impl Default for SecureTrie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SecureIter<'a, D> {
+    inner: TrieIter<'a, D>,
+    preimages: Option<&'a HashMap<[u8; 32], Vec<u8>>>,
+    failed: bool,
+}
+
+impl<'a, D: NodeDb> Iterator for SecureIter<'a, D> {
+    type Item = Result<(Vec<u8>, Vec<u8>), TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let (hashed_key, value) = match self.inner.next()? {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err)),
+        };
+
+        // a key that is not 32 bytes long, e.g. from a trie that was not
+        // built as a secure trie, cannot have a preimage.
+        let preimage = <[u8; 32]>::try_from(hashed_key.as_slice())
+            .ok()
+            .and_then(|hash| self.preimages?.get(&hash));
+        match preimage {
+            Some(key) => Some(Ok((key.clone(), value))),
+            None => {
+                self.failed = true;
+                Some(Err(TrieError::MissingPreimage(hashed_key)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trie::EMPTY_ROOT;
+
+    fn sample_trie() -> SecureTrie {
+        let mut trie = SecureTrie::new();
+        trie.enable_preimages();
+        trie.insert(b"doe", b"reindeer").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"dogglesworth", b"cat").unwrap();
+        trie
+    }
+
+    #[test]
+    fn test_secure_root_matches_geth() {
+        assert_eq!(
+            hex::encode(sample_trie().root_hash()),
+            "d4cd937e4a4368d7931a9cf51686b7e10abb3dce38a39000fd7902a092b64585"
+        );
+    }
+
+    #[test]
+    fn test_secure_root_is_root_of_hashed_keys() {
+        let mut plain = EthTrie::new();
+        plain.insert(&keccak256(b"doe"), b"reindeer").unwrap();
+        plain.insert(&keccak256(b"dog"), b"puppy").unwrap();
+        plain.insert(&keccak256(b"dogglesworth"), b"cat").unwrap();
+
+        assert_eq!(sample_trie().root_hash(), plain.root_hash());
+    }
+
+    #[test]
+    fn test_secure_get_and_remove() {
+        let mut trie = sample_trie();
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+        assert_eq!(trie.get(b"cat").unwrap(), None);
+        assert_eq!(trie.trie().get(b"dog").unwrap(), None, "raw keys must not be stored");
+
+        assert_eq!(trie.remove(b"dog").unwrap(), Some(b"puppy".to_vec()));
+        assert_eq!(trie.remove(b"doe").unwrap(), Some(b"reindeer".to_vec()));
+        assert_eq!(trie.remove(b"dogglesworth").unwrap(), Some(b"cat".to_vec()));
+        assert_eq!(trie.root_hash(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_secure_proofs() {
+        let trie = sample_trie();
+        let root = trie.root_hash();
+
+        let proof = trie.get_proof(b"dogglesworth").unwrap();
+        assert_eq!(SecureTrie::verify_proof(&root, b"dogglesworth", &proof), Ok(Some(b"cat".to_vec())));
+
+        let proof = trie.get_proof(b"horse").unwrap();
+        assert_eq!(SecureTrie::verify_proof(&root, b"horse", &proof), Ok(None));
+    }
+
+    #[test]
+    fn test_secure_iter_returns_preimages_in_hash_order() {
+        let trie = sample_trie();
+        let entries = trie.iter().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let mut expected = vec![
+            (b"doe".to_vec(), b"reindeer".to_vec()),
+            (b"dog".to_vec(), b"puppy".to_vec()),
+            (b"dogglesworth".to_vec(), b"cat".to_vec()),
+        ];
+        expected.sort_by_key(|(key, _)| keccak256(key));
+        assert_eq!(entries, expected);
+        assert_eq!(trie.preimage(&keccak256(b"dog")), Some(&b"dog"[..]));
+    }
+
+    #[test]
+    fn test_secure_iter_without_preimages() {
+        let mut trie = SecureTrie::new();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
+
+        let mut iter = trie.iter();
+        assert!(matches!(iter.next(), Some(Err(TrieError::MissingPreimage(_)))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_secure_iter_over_unhashed_keys() {
+        let mut plain = EthTrie::new();
+        plain.insert(b"dog", b"puppy").unwrap();
+        let root = plain.commit().unwrap();
+
+        let mut trie = SecureTrie::from_root(root, plain.into_db());
+        trie.enable_preimages();
+        let mut iter = trie.iter();
+        assert!(matches!(iter.next(), Some(Err(TrieError::MissingPreimage(key))) if key == b"dog"));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_secure_commit_and_reopen() {
+        let mut trie = sample_trie();
+        let root = trie.commit().unwrap();
+
+        let reopened = SecureTrie::from_root(root, trie.into_trie().into_db());
+        assert_eq!(reopened.root_hash(), root);
+        assert_eq!(reopened.get(b"doe").unwrap(), Some(b"reindeer".to_vec()));
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+
+use primitive_types::U256;
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
+
+use crate::db::{MemoryDb, NodeDb, SharedDb};
+use crate::error::TrieError;
+use crate::secure::SecureTrie;
+use crate::trie::EMPTY_ROOT;
+
+/// keccak256 of empty contract code.
+pub const EMPTY_CODE_HASH: [u8; 32] = [
+    0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c,
+    0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+    0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b,
+    0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
+];
+
+pub type Address = [u8; 20];
+
+/// an account record as stored in the state trie:
+/// `rlp([nonce, balance, storage_root, code_hash])`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: u64,
+    pub balance: U256,
+    pub storage_root: [u8; 32],
+    pub code_hash: [u8; 32],
+}
+
+impl Account {
+    /// an account without storage or code.
+    pub fn new(nonce: u64, balance: U256) -> Self {
+        Account {
+            nonce,
+            balance,
+            storage_root: EMPTY_ROOT,
+            code_hash: EMPTY_CODE_HASH,
+        }
+    }
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Account::new(0, U256::zero())
+    }
+}
+
+impl Encodable for Account {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(4);
+        s.append(&self.nonce);
+        s.append(&self.balance);
+        s.append(&self.storage_root.as_slice());
+        s.append(&self.code_hash.as_slice());
+    }
+}
+
+impl Decodable for Account {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 4 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+
+        let hash_at = |index| -> Result<[u8; 32], DecoderError> {
+            let data = rlp.at(index)?.data()?;
+            if data.len() != 32 {
+                return Err(DecoderError::Custom("account hash must be 32 bytes"));
+            }
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(data);
+            Ok(hash)
+        };
+
+        Ok(Account {
+            nonce: rlp.val_at(0)?,
+            balance: rlp.val_at(1)?,
+            storage_root: hash_at(2)?,
+            code_hash: hash_at(3)?,
+        })
+    }
+}
+
+/// the world state: accounts keyed by `keccak256(address)` in a secure trie,
+/// whose root is a block's `stateRoot`.
+///
+/// each account's storage lives in its own secure trie over the same node
+/// database. storage writes are held in those tries until `commit`, which
+/// stores them and rewrites the affected accounts' `storage_root`; until
+/// then `root_hash` and `get_account` do not reflect pending storage.
+pub struct StateTrie<D = MemoryDb> {
+    trie: SecureTrie<SharedDb<D>>,
+    db: SharedDb<D>,
+    storage: HashMap<Address, SecureTrie<SharedDb<D>>>,
+}
+
+impl StateTrie {
+    pub fn new() -> Self {
+        StateTrie::with_db(MemoryDb::new())
+    }
+}
+
+impl<D: NodeDb> StateTrie<D> {
+    pub fn with_db(db: D) -> Self {
+        StateTrie::from_root(EMPTY_ROOT, db)
+    }
+
+    /// opens the state with the given root, loading nodes from `db` on demand.
+    pub fn from_root(state_root: [u8; 32], db: D) -> Self {
+        let db = SharedDb::new(db);
+        StateTrie {
+            trie: SecureTrie::from_root(state_root, db.clone()),
+            db,
+            storage: HashMap::new(),
+        }
+    }
+
+    /// the underlying secure trie holding the rlp-encoded accounts.
+    pub fn trie(&self) -> &SecureTrie<SharedDb<D>> {
+        &self.trie
+    }
+
+    /// the node database, including any storage written by `commit`. fails
+    /// and returns the shared handle if a clone of it taken through
+    /// `trie()` is still alive.
+    pub fn into_db(self) -> Result<D, SharedDb<D>> {
+        let StateTrie { trie, db, storage } = self;
+        drop(trie);
+        drop(storage);
+        db.into_inner()
+    }
+
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.trie.root_hash()
+    }
+
+    pub fn get_account(&self, address: &Address) -> Result<Option<Account>, TrieError> {
+        match self.trie.get(address)? {
+            Some(encoded) => Ok(Some(Self::decode_account(address, &encoded)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_account(&mut self, address: &Address, account: &Account) -> Result<(), TrieError> {
+        self.trie.insert(address, &rlp::encode(account))
+    }
+
+    /// removes the account, together with any pending storage writes, and
+    /// returns what was stored for it.
+    pub fn delete_account(&mut self, address: &Address) -> Result<Option<Account>, TrieError> {
+        self.storage.remove(address);
+        match self.trie.remove(address)? {
+            Some(encoded) => Ok(Some(Self::decode_account(address, &encoded)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// proof of the account record under `stateRoot`; verify it with
+    /// `SecureTrie::verify_proof` and decode the value as an `Account`.
+    pub fn get_proof(&self, address: &Address) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.trie.get_proof(address)
+    }
+
+    /// the value of a storage slot, including uncommitted writes. slots that
+    /// were never written read as zero.
+    pub fn get_storage(&self, address: &Address, slot: U256) -> Result<U256, TrieError> {
+        let key = slot_key(slot);
+        let encoded = match self.storage.get(address) {
+            Some(storage) => storage.get(&key)?,
+            None => self.open_storage(address)?.get(&key)?,
+        };
+
+        match encoded {
+            Some(encoded) => rlp::decode(&encoded).map_err(|source| TrieError::InvalidStorage {
+                address: *address,
+                source,
+            }),
+            None => Ok(U256::zero()),
+        }
+    }
+
+    /// writes a storage slot; writing zero deletes it. the account's
+    /// `storage_root` is updated on the next `commit`, which creates the
+    /// account if it does not exist yet.
+    pub fn set_storage(&mut self, address: &Address, slot: U256, value: U256) -> Result<(), TrieError> {
+        if let Some(storage) = self.storage.get_mut(address) {
+            return Self::write_slot(storage, slot, value);
+        }
+        let mut storage = self.open_storage(address)?;
+        Self::write_slot(&mut storage, slot, value)?;
+        self.storage.insert(*address, storage);
+        Ok(())
+    }
+
+    fn write_slot(storage: &mut SecureTrie<SharedDb<D>>, slot: U256, value: U256) -> Result<(), TrieError> {
+        let key = slot_key(slot);
+        if value.is_zero() {
+            storage.remove(&key)?;
+        } else {
+            storage.insert(&key, &rlp::encode(&value))?;
+        }
+        Ok(())
+    }
+
+    /// the storage root an account will have after the next `commit`.
+    pub fn storage_root(&self, address: &Address) -> Result<[u8; 32], TrieError> {
+        match self.storage.get(address) {
+            Some(storage) => Ok(storage.root_hash()),
+            None => Ok(self.get_account(address)?.map_or(EMPTY_ROOT, |account| account.storage_root)),
+        }
+    }
+
+    /// stores every modified storage trie, points each account at its new
+    /// storage root, then stores the state trie and returns the state root.
+    pub fn commit(&mut self) -> Result<[u8; 32], TrieError> {
+        let mut addresses: Vec<Address> = self.storage.keys().copied().collect();
+        addresses.sort_unstable();
+
+        for address in addresses {
+            let storage_root = match self.storage.get_mut(&address) {
+                Some(storage) => storage.commit()?,
+                None => continue,
+            };
+            let mut account = self.get_account(&address)?.unwrap_or_default();
+            if account.storage_root != storage_root {
+                account.storage_root = storage_root;
+                self.set_account(&address, &account)?;
+            }
+            self.storage.remove(&address);
+        }
+
+        self.trie.commit()
+    }
+
+    pub(crate) fn open_storage(&self, address: &Address) -> Result<SecureTrie<SharedDb<D>>, TrieError> {
+        let storage_root = self.get_account(address)?.map_or(EMPTY_ROOT, |account| account.storage_root);
+        Ok(SecureTrie::from_root(storage_root, self.db.clone()))
+    }
+
+    fn decode_account(address: &Address, encoded: &[u8]) -> Result<Account, TrieError> {
+        rlp::decode(encoded).map_err(|source| TrieError::InvalidAccount {
+            address: *address,
+            source,
+        })
+    }
+}
+
+/// storage tries are keyed by the slot as a 32-byte big-endian word.
+pub(crate) fn slot_key(slot: U256) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    slot.to_big_endian(&mut key);
+    key
+}
+
+impl Default for StateTrie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::keccak256;
+
+    fn address(byte: u8) -> Address {
+        [byte; 20]
+    }
+
+    #[test]
+    fn test_empty_code_hash() {
+        assert_eq!(keccak256(&[]), EMPTY_CODE_HASH);
+    }
+
+    #[test]
+    fn test_empty_account_encoding() {
+        let encoded = rlp::encode(&Account::default());
+        let expected = format!("f8448080a0{}a0{}", hex::encode(EMPTY_ROOT), hex::encode(EMPTY_CODE_HASH));
+        assert_eq!(hex::encode(&encoded), expected);
+    }
+
+    #[test]
+    fn test_account_round_trip() {
+        let account = Account {
+            nonce: 0x1234,
+            balance: U256::from_dec_str("1000000000000000000000000").unwrap(),
+            storage_root: [0x11; 32],
+            code_hash: [0x22; 32],
+        };
+        let decoded: Account = rlp::decode(&rlp::encode(&account)).unwrap();
+        assert_eq!(decoded, account);
+    }
+
+    #[test]
+    fn test_account_decode_rejects_malformed_records() {
+        let mut s = RlpStream::new_list(3);
+        s.append(&1u64).append(&2u64).append(&EMPTY_ROOT.as_slice());
+        assert!(rlp::decode::<Account>(&s.out()).is_err());
+
+        let mut s = RlpStream::new_list(4);
+        s.append(&1u64).append(&2u64).append(&EMPTY_ROOT.as_slice()).append(&vec![0xc5u8; 20]);
+        assert!(rlp::decode::<Account>(&s.out()).is_err());
+    }
+
+    #[test]
+    fn test_state_trie_accounts() {
+        let mut state = StateTrie::new();
+        assert_eq!(state.root_hash(), EMPTY_ROOT);
+        assert_eq!(state.get_account(&address(1)).unwrap(), None);
+
+        let alice = Account::new(1, U256::from(1_000u64));
+        let bob = Account::new(0, U256::exp10(18));
+        state.set_account(&address(1), &alice).unwrap();
+        state.set_account(&address(2), &bob).unwrap();
+
+        assert_eq!(state.get_account(&address(1)).unwrap(), Some(alice.clone()));
+        assert_eq!(state.get_account(&address(2)).unwrap(), Some(bob));
+
+        assert_eq!(state.delete_account(&address(2)).unwrap().map(|a| a.nonce), Some(0));
+        assert_eq!(state.delete_account(&address(2)).unwrap(), None);
+
+        let mut expected = StateTrie::new();
+        expected.set_account(&address(1), &alice).unwrap();
+        assert_eq!(state.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_state_root_is_secure_trie_of_encoded_accounts() {
+        let account = Account::new(7, U256::from(42u64));
+        let mut state = StateTrie::new();
+        state.set_account(&address(0xaa), &account).unwrap();
+
+        let mut trie = SecureTrie::new();
+        trie.insert(&address(0xaa), &rlp::encode(&account)).unwrap();
+        assert_eq!(state.root_hash(), trie.root_hash());
+    }
+
+    #[test]
+    fn test_state_proof_verifies_account() {
+        let account = Account::new(3, U256::from(99u64));
+        let mut state = StateTrie::new();
+        state.set_account(&address(1), &account).unwrap();
+        state.set_account(&address(2), &Account::default()).unwrap();
+
+        let proof = state.get_proof(&address(1)).unwrap();
+        let value = SecureTrie::verify_proof(&state.root_hash(), &address(1), &proof).unwrap().unwrap();
+        assert_eq!(rlp::decode::<Account>(&value).unwrap(), account);
+    }
+
+    #[test]
+    fn test_state_commit_and_reopen() {
+        let mut state = StateTrie::new();
+        for i in 0..50u8 {
+            state.set_account(&address(i), &Account::new(i as u64, U256::from(i))).unwrap();
+        }
+        let root = state.commit().unwrap();
+
+        let reopened = StateTrie::from_root(root, state.into_db().ok().unwrap());
+        assert_eq!(reopened.get_account(&address(17)).unwrap(), Some(Account::new(17, U256::from(17u64))));
+    }
+
+    #[test]
+    fn test_state_reports_invalid_account() {
+        let mut trie = SecureTrie::new();
+        trie.insert(&address(1), b"not an account").unwrap();
+        let root = trie.commit().unwrap();
+
+        let state = StateTrie::from_root(root, trie.into_trie().into_db());
+        assert!(matches!(
+            state.get_account(&address(1)),
+            Err(TrieError::InvalidAccount { address: a, .. }) if a == address(1)
+        ));
+    }
+
+    fn slot(n: u64) -> U256 {
+        U256::from(n)
+    }
+
+    #[test]
+    fn test_storage_reads_and_writes() {
+        let mut state = StateTrie::new();
+        state.set_account(&address(1), &Account::new(1, U256::from(10u64))).unwrap();
+        assert_eq!(state.get_storage(&address(1), slot(0)).unwrap(), U256::zero());
+
+        state.set_storage(&address(1), slot(0), U256::from(0x1234u64)).unwrap();
+        state.set_storage(&address(1), U256::MAX, U256::MAX).unwrap();
+        assert_eq!(state.get_storage(&address(1), slot(0)).unwrap(), U256::from(0x1234u64));
+        assert_eq!(state.get_storage(&address(1), U256::MAX).unwrap(), U256::MAX);
+
+        state.set_storage(&address(1), slot(0), U256::zero()).unwrap();
+        assert_eq!(state.get_storage(&address(1), slot(0)).unwrap(), U256::zero());
+    }
+
+    #[test]
+    fn test_storage_root_is_secure_trie_of_stripped_values() {
+        let mut state = StateTrie::new();
+        state.set_storage(&address(1), slot(0), U256::from(1u64)).unwrap();
+        state.set_storage(&address(1), slot(1), U256::from(0x0100u64)).unwrap();
+
+        let mut expected = SecureTrie::new();
+        let mut key = [0u8; 32];
+        expected.insert(&key, &[0x01]).unwrap();
+        key[31] = 1;
+        expected.insert(&key, &[0x82, 0x01, 0x00]).unwrap();
+
+        assert_eq!(state.storage_root(&address(1)).unwrap(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_commit_links_storage_roots_into_state() {
+        let mut state = StateTrie::new();
+        state.set_account(&address(1), &Account::new(5, U256::from(100u64))).unwrap();
+        let root_before = state.root_hash();
+
+        state.set_storage(&address(1), slot(7), U256::from(42u64)).unwrap();
+        state.set_storage(&address(2), slot(1), U256::from(1u64)).unwrap();
+        assert_eq!(state.root_hash(), root_before, "storage is only linked in on commit");
+        let storage_root = state.storage_root(&address(1)).unwrap();
+
+        let state_root = state.commit().unwrap();
+        let account = state.get_account(&address(1)).unwrap().unwrap();
+        assert_eq!(account.nonce, 5);
+        assert_eq!(account.storage_root, storage_root);
+        assert_ne!(account.storage_root, EMPTY_ROOT);
+        // writing storage to a missing account creates it.
+        assert_eq!(state.get_account(&address(2)).unwrap().map(|a| a.nonce), Some(0));
+
+        let mut expected = StateTrie::new();
+        expected.set_account(&address(1), &account).unwrap();
+        expected.set_account(&address(2), &state.get_account(&address(2)).unwrap().unwrap()).unwrap();
+        assert_eq!(state_root, expected.root_hash());
+    }
+
+    #[test]
+    fn test_storage_survives_reopen() {
+        let mut state = StateTrie::new();
+        for i in 0..20 {
+            state.set_storage(&address(9), slot(i), U256::from(i + 1)).unwrap();
+        }
+        let root = state.commit().unwrap();
+
+        let mut reopened = StateTrie::from_root(root, state.into_db().ok().unwrap());
+        assert_eq!(reopened.get_storage(&address(9), slot(11)).unwrap(), U256::from(12u64));
+
+        reopened.set_storage(&address(9), slot(11), U256::zero()).unwrap();
+        let root = reopened.commit().unwrap();
+        let reopened = StateTrie::from_root(root, reopened.into_db().ok().unwrap());
+        assert_eq!(reopened.get_storage(&address(9), slot(11)).unwrap(), U256::zero());
+        assert_eq!(reopened.get_storage(&address(9), slot(12)).unwrap(), U256::from(13u64));
+    }
+
+    #[test]
+    fn test_into_db_fails_while_handle_is_shared() {
+        let mut state = StateTrie::new();
+        state.set_storage(&address(3), slot(1), U256::one()).unwrap();
+        let root = state.commit().unwrap();
+
+        let handle = state.trie().db().clone();
+        let shared = state.into_db().err().unwrap();
+        drop(handle);
+        let db = shared.into_inner().ok().unwrap();
+        assert!(!StateTrie::from_root(root, db).get_storage(&address(3), slot(1)).unwrap().is_zero());
+    }
+
+    #[test]
+    fn test_clearing_all_storage_restores_empty_root() {
+        let mut state = StateTrie::new();
+        state.set_account(&address(1), &Account::default()).unwrap();
+        let empty_state = state.root_hash();
+
+        state.set_storage(&address(1), slot(3), U256::from(3u64)).unwrap();
+        state.commit().unwrap();
+        state.set_storage(&address(1), slot(3), U256::zero()).unwrap();
+
+        assert_eq!(state.commit().unwrap(), empty_state);
+        assert_eq!(state.get_account(&address(1)).unwrap().unwrap().storage_root, EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_delete_account_drops_pending_storage() {
+        let mut state = StateTrie::new();
+        state.set_storage(&address(1), slot(1), U256::from(1u64)).unwrap();
+        state.delete_account(&address(1)).unwrap();
+
+        assert_eq!(state.commit().unwrap(), EMPTY_ROOT);
+    }
+}
@@ -1,97 +1,646 @@
-use tiny_keccak::{Hasher, Keccak};
-use serde::{Deserialize, Serialize};
-
-use crate::nibbles::Nibbles;
-use crate::node::Node;
-
-#[derive(Serialize, Deserialize)]
-pub struct EthTrie {
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rlp::DecoderError;
+
+use crate::db::{MemoryDb, NodeDb};
+use crate::error::{ProofError, TrieError};
+use crate::journal::{CheckpointId, Journal, UndoEntry};
+use crate::nibbles::{nibbles_to_bytes, Nibbles};
+use crate::node::{keccak256, Node};
+
+/// keccak256 of the rlp encoding of an empty trie.
+pub const EMPTY_ROOT: [u8; 32] = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6,
+    0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0,
+    0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+/// how many branch levels below the root `root_hash` and `commit` hash in
+/// parallel: up to 16 tasks at the first level and 256 at the second, each
+/// for a subtree large enough to be worth one.
+#[cfg(feature = "parallel")]
+const PARALLEL_LEVELS: usize = 2;
+
+/// proof items by hash, with their position in the proof.
+pub(crate) type ProofNodes<'a> = HashMap<[u8; 32], (usize, &'a [u8])>;
+
+/// a write in `apply_batch`: the key's nibble path and its new value, or
+/// `None` to remove it.
+type BatchOp = (Vec<u8>, Option<Vec<u8>>);
+
+/// encoded nodes by hash, as recorded for a witness.
+type Witness = HashMap<[u8; 32], Vec<u8>>;
+
+/// a merkle patricia trie over a node database. nodes touched since the last
+/// `commit` live in memory under `root`; everything else is referenced by
+/// `Node::Hash` and loaded from `db` when a lookup reaches it.
+pub struct EthTrie<D = MemoryDb> {
     root: Box<Node>,
+    db: D,
+    witness: RefCell<Option<Witness>>,
+    journal: Journal,
 }
 
 impl EthTrie {
     pub fn new() -> Self {
+        EthTrie::with_db(MemoryDb::new())
+    }
+
+    /// checks `proof` against `root_hash` and returns what it proves about
+    /// `key`: `Ok(Some(value))` if the key is present, `Ok(None)` if the
+    /// proof shows the key is absent, and `Err` if the proof itself is
+    /// invalid (a referenced node is missing or does not decode).
+    pub fn verify_proof(
+        root_hash: &[u8; 32],
+        key: &[u8],
+        proof: &[Vec<u8>],
+    ) -> Result<Option<Vec<u8>>, ProofError> {
+        if *root_hash == EMPTY_ROOT {
+            return Ok(None);
+        }
+
+        let nibbles = Nibbles::from_raw(key, false);
+        let nibbles_vec = nibbles.as_slice().to_vec();
+        Self::verify_proof_recursive(&nibbles_vec, Node::Hash(*root_hash), &Self::proof_nodes(proof), 0)
+    }
+
+    /// checks a proof from `get_multiproof` and returns what it proves about
+    /// each key, in the order given. fails if any key's path leaves the
+    /// proven nodes, exactly as `verify_proof` would for that key.
+    pub fn verify_multiproof<I>(root_hash: &[u8; 32], keys: I, nodes: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, ProofError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let nodes = Self::proof_nodes(nodes);
+        keys.into_iter()
+            .map(|key| {
+                if *root_hash == EMPTY_ROOT {
+                    return Ok(None);
+                }
+                let nibbles = Nibbles::from_raw(key.as_ref(), false);
+                Self::verify_proof_recursive(nibbles.as_slice(), Node::Hash(*root_hash), &nodes, 0)
+            })
+            .collect()
+    }
+
+    /// indexes proof items by their hash. items are looked up by hash rather
+    /// than by position, so every step down a path has to be linked to its
+    /// parent's reference.
+    pub(crate) fn proof_nodes(proof: &[Vec<u8>]) -> ProofNodes<'_> {
+        proof
+            .iter()
+            .enumerate()
+            .map(|(index, item)| (keccak256(item), (index, item.as_slice())))
+            .collect()
+    }
+
+    fn verify_proof_recursive(
+        nibbles: &[u8],
+        node: Node,
+        nodes: &ProofNodes,
+        depth: usize,
+    ) -> Result<Option<Vec<u8>>, ProofError> {
+        match node {
+            Node::Null => Ok(None),
+            Node::Leaf { key, value, .. } => {
+                if key == nibbles {
+                    Ok(Some(value))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Extension { prefix, next, .. } => {
+                if !nibbles.starts_with(&prefix) {
+                    return Ok(None);
+                }
+                Self::verify_proof_recursive(&nibbles[prefix.len()..], *next, nodes, depth + 1)
+            }
+            Node::Branch { mut children, value, .. } => {
+                if nibbles.is_empty() {
+                    Ok(value)
+                } else {
+                    let child = std::mem::take(&mut *children[nibbles[0] as usize]);
+                    Self::verify_proof_recursive(&nibbles[1..], child, nodes, depth + 1)
+                }
+            }
+            Node::Hash(hash) => {
+                let resolved = Self::proof_node(nodes, hash, depth)?;
+                Self::verify_proof_recursive(nibbles, resolved, nodes, depth)
+            }
+        }
+    }
+
+    /// the proof item with the given hash, decoded. `depth` is only used to
+    /// report errors.
+    pub(crate) fn proof_node(nodes: &ProofNodes, hash: [u8; 32], depth: usize) -> Result<Node, ProofError> {
+        let &(index, item) = nodes
+            .get(&hash)
+            .ok_or(ProofError::MissingNode { hash, depth })?;
+        let invalid = |reason| ProofError::InvalidNode { index, depth, reason };
+        match rlp::decode::<Node>(item).map_err(invalid)? {
+            Node::Hash(_) => Err(invalid(DecoderError::RlpExpectedToBeList)),
+            resolved => Ok(resolved),
+        }
+    }
+
+    /// a trie holding `root` in memory, with nothing in its database.
+    pub(crate) fn from_node(root: Node) -> Self {
+        EthTrie {
+            root: Box::new(root),
+            db: MemoryDb::new(),
+            witness: RefCell::new(None),
+            journal: Journal::default(),
+        }
+    }
+}
+
+impl<D: NodeDb> EthTrie<D> {
+    pub fn with_db(db: D) -> Self {
         EthTrie {
             root: Box::new(Node::Null),
+            db,
+            witness: RefCell::new(None),
+            journal: Journal::default(),
+        }
+    }
+
+    /// opens the trie with the given root, loading nodes from `db` on demand.
+    pub fn from_root(root_hash: [u8; 32], db: D) -> Self {
+        let root = if root_hash == EMPTY_ROOT {
+            Node::Null
+        } else {
+            Node::Hash(root_hash)
+        };
+        EthTrie {
+            root: Box::new(root),
+            db,
+            witness: RefCell::new(None),
+            journal: Journal::default(),
         }
     }
 
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    pub fn db_mut(&mut self) -> &mut D {
+        &mut self.db
+    }
+
+    pub fn into_db(self) -> D {
+        self.db
+    }
+
+    /// the keccak hash of the root node. node references are cached, so
+    /// after a small update only the nodes on the modified paths are
+    /// re-encoded.
     pub fn root_hash(&self) -> [u8; 32] {
-        let encoded = rlp::encode(&*self.root);
-        let mut hasher = Keccak::v256();
-        let mut output = [0u8; 32];
-        hasher.update(&encoded);
-        hasher.finalize(&mut output);
-        output
+        #[cfg(feature = "parallel")]
+        self.root.hash_in_parallel(PARALLEL_LEVELS);
+        let reference = self.root.reference();
+        if reference.len() == 32 {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(reference);
+            return hash;
+        }
+
+        // a root that encodes to fewer than 32 bytes is hashed anyway.
+        keccak256(reference)
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError> {
+        // the prior value is only needed while a checkpoint may be reverted.
+        let previous = if self.journal.is_active() { Some(self.get(key)?) } else { None };
+        self.insert_unjournaled(key, value)?;
+        if let Some(previous) = previous {
+            self.journal.record(key, previous);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        let removed = self.remove_unjournaled(key)?;
+        if self.journal.is_active() && removed.is_some() {
+            self.journal.record(key, removed.clone());
+        }
+        Ok(removed)
+    }
+
+    /// applies a batch of writes, `Some(value)` to insert and `None` to
+    /// remove, and returns the new root hash. the keys are sorted and
+    /// applied in a single walk down the trie, so nodes shared by several
+    /// keys are visited and rewritten once; if a key appears more than once
+    /// its last write wins. on error the trie may hold part of the batch.
+    pub fn apply_batch<I, K, V>(&mut self, writes: I) -> Result<[u8; 32], TrieError>
+    where
+        I: IntoIterator<Item = (K, Option<V>)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let writes: BTreeMap<Vec<u8>, Option<Vec<u8>>> = writes
+            .into_iter()
+            .map(|(key, value)| (key.as_ref().to_vec(), value.map(|value| value.as_ref().to_vec())))
+            .collect();
+
+        let mut ops: Vec<BatchOp> = writes
+            .into_iter()
+            .map(|(key, value)| (Nibbles::from_raw(&key, false).as_slice().to_vec(), value))
+            .collect();
+        let mut undo = self.journal.is_active().then(Vec::new);
+        let mut root = std::mem::take(&mut *self.root);
+        let applied = self.apply_at(&mut root, &mut ops, 0, &mut undo);
+        *self.root = root;
+        // writes applied before a failure are journaled too, so a checkpoint
+        // can still revert them.
+        for (key, previous) in undo.into_iter().flatten() {
+            self.journal.record(&key, previous);
+        }
+        applied?;
+        Ok(self.root_hash())
+    }
+
+    /// opens a checkpoint that `revert_to` can later return the trie to.
+    /// checkpoints nest to any depth; while one is open, each write records
+    /// the key's prior value in an undo journal.
+    pub fn checkpoint(&mut self) -> CheckpointId {
+        self.journal.checkpoint()
+    }
+
+    /// undoes every write since `id` was taken, restoring its root hash, and
+    /// closes `id` together with the checkpoints nested inside it.
+    ///
+    /// writes are undone most recent first. if undoing one fails, e.g. on a
+    /// node missing from the database, the writes not yet undone stay in the
+    /// journal and `id` stays open, so the revert can be retried.
+    pub fn revert_to(&mut self, id: CheckpointId) -> Result<(), TrieError> {
+        let start = self.journal.start_of(id)?;
+        while let Some((key, previous)) = self.journal.pop_from(start) {
+            let undone = match &previous {
+                Some(value) => self.insert_unjournaled(&key, value),
+                None => self.remove_unjournaled(&key).map(|_| ()),
+            };
+            if let Err(err) = undone {
+                self.journal.record(&key, previous);
+                return Err(err);
+            }
+        }
+        self.journal.close(id)
+    }
+
+    /// closes `id` and the checkpoints nested inside it, keeping their
+    /// writes. an enclosing checkpoint can still revert them.
+    pub fn discard(&mut self, id: CheckpointId) -> Result<(), TrieError> {
+        self.journal.discard(id)
+    }
+
+    fn insert_unjournaled(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError> {
+        let nibbles = Nibbles::from_raw(key, false);
+        let nibbles_vec = nibbles.as_slice().to_vec();
+        let mut root = std::mem::take(&mut *self.root);
+        let inserted = self.insert_at(&mut root, &nibbles_vec, value.to_vec());
+        *self.root = root;
+        inserted.map(|_| ())
     }
 
-    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+    fn remove_unjournaled(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
         let nibbles = Nibbles::from_raw(key, false);
         let nibbles_vec = nibbles.as_slice().to_vec();
-        self.root = Box::new(Self::insert_at(*self.root.clone(), &nibbles_vec, value.to_vec()));
+        let mut root = std::mem::take(&mut *self.root);
+        let removed = self.remove_at(&mut root, &nibbles_vec);
+        *self.root = root;
+        removed
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
         let nibbles = Nibbles::from_raw(key, false);
         let nibbles_vec = nibbles.as_slice().to_vec();
-        Self::get_at(&self.root, &nibbles_vec)
+        self.get_at(&self.root, &nibbles_vec)
     }
 
-    pub fn get_proof(&self, key: &[u8]) -> Vec<Vec<u8>> {
+    pub fn get_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, TrieError> {
         let nibbles = Nibbles::from_raw(key, false);
         let nibbles_vec = nibbles.as_slice().to_vec();
         let mut proof = Vec::new();
-        Self::get_proof_at(&self.root, &nibbles_vec, &mut proof);
-        proof
+        self.get_proof_at(&self.root, &nibbles_vec, &mut proof)?;
+        Ok(proof)
     }
 
+    /// a single proof for all of `keys`. each node referenced by hash on any
+    /// of their paths appears once, however many keys pass through it;
+    /// nodes embedded in their parent are left out since the parent carries
+    /// them.
+    pub fn get_multiproof<I>(&self, keys: I) -> Result<Vec<Vec<u8>>, TrieError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut paths: Vec<Vec<u8>> = keys
+            .into_iter()
+            .map(|key| Nibbles::from_raw(key.as_ref(), false).as_slice().to_vec())
+            .collect();
+        paths.sort();
+        paths.dedup();
 
-    pub fn verify_proof(
-        root_hash: &[u8; 32],
-        key: &[u8],
-        proof: &[Vec<u8>],
-    ) -> Option<Vec<u8>> {
-        if proof.is_empty() {
-            return None;
+        let mut proof = Vec::new();
+        if !paths.is_empty() && !matches!(*self.root, Node::Null) {
+            self.multiproof_at(&self.root, &paths, true, &mut HashSet::new(), &mut proof)?;
         }
+        Ok(proof)
+    }
 
-        let nibbles = Nibbles::from_raw(key, false);
-        let nibbles_vec = nibbles.as_slice().to_vec();
-
-        let first_item = &proof[0];
-        let computed_hash = Self::compute_hash(first_item);
-        if computed_hash != *root_hash {
-            return None;
+    /// writes every node modified since the last commit to the database and
+    /// returns the new root hash. afterwards the trie only holds the root
+    /// reference and resolves everything else from the database.
+    pub fn commit(&mut self) -> Result<[u8; 32], TrieError> {
+        #[cfg(feature = "parallel")]
+        self.root.hash_in_parallel(PARALLEL_LEVELS);
+        Self::commit_node(&mut self.db, &mut self.root)?;
+
+        match &*self.root {
+            Node::Null => Ok(EMPTY_ROOT),
+            Node::Hash(hash) => Ok(*hash),
+            root => {
+                // the root is always stored by hash, even when it is small
+                // enough to be embedded.
+                let encoded = rlp::encode(root).to_vec();
+                let hash = keccak256(&encoded);
+                self.db.put(hash, encoded)?;
+                *self.root = Node::Hash(hash);
+                Ok(hash)
+            }
         }
+    }
 
-        Self::verify_proof_recursive(&nibbles_vec, proof, 0)
+    /// starts recording a witness for the operations that follow: every
+    /// node they load from the database, plus the nodes already held in
+    /// memory, since those are not in the database either. replaying the
+    /// operations on `PartialTrie::from_witness` with the current root then
+    /// gives the same results. starting from a committed trie keeps the
+    /// witness to just the nodes touched.
+    pub fn start_witness(&mut self) {
+        let mut witness = Witness::new();
+        Self::record_in_memory(&self.root, true, &mut witness);
+        *self.witness.get_mut() = Some(witness);
+    }
+
+    /// stops recording and returns the witness, ordered by node hash. empty
+    /// if no recording was started.
+    pub fn take_witness(&mut self) -> Vec<Vec<u8>> {
+        let mut nodes: Vec<([u8; 32], Vec<u8>)> = self.witness.get_mut().take().unwrap_or_default().into_iter().collect();
+        nodes.sort_unstable_by_key(|(hash, _)| *hash);
+        nodes.into_iter().map(|(_, node)| node).collect()
     }
 
     pub fn print_tree(&self) {
         println!("trie structure:");
         println!("root hash: {}", hex::encode(self.root_hash()));
-        Self::print_node(&self.root, 0, "");
+        self.print_node(&self.root, 0, "");
+    }
+
+    pub(crate) fn root_node(&self) -> &Node {
+        &self.root
     }
 
-    fn insert_at(node: Node, nibbles: &[u8], value: Vec<u8>) -> Node {
+    #[cfg(test)]
+    pub(crate) fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub(crate) fn resolve(&self, hash: &[u8; 32]) -> Result<Node, TrieError> {
+        let encoded = self.db.get(hash)?.ok_or(TrieError::MissingNode(*hash))?;
+        if let Some(witness) = self.witness.borrow_mut().as_mut() {
+            witness.insert(*hash, encoded.clone());
+        }
+        let node: Node = rlp::decode(&encoded).map_err(|source| TrieError::InvalidNode {
+            hash: *hash,
+            source,
+        })?;
+        // only the root can be stored while small enough to be embedded, and
+        // its reference is then the encoding rather than the hash.
+        if encoded.len() >= 32 {
+            node.set_hash(*hash);
+        }
+        Ok(node)
+    }
+
+    /// adds the in-memory nodes below `node` that are referenced by hash.
+    fn record_in_memory(node: &Node, is_root: bool, witness: &mut Witness) {
+        if let Node::Null | Node::Hash(_) = node {
+            return;
+        }
+        if is_root || node.reference().len() == 32 {
+            let encoded = rlp::encode(node).to_vec();
+            witness.insert(keccak256(&encoded), encoded);
+        }
         match node {
+            Node::Extension { next, .. } => Self::record_in_memory(next, false, witness),
+            Node::Branch { children, .. } => {
+                for child in children.iter() {
+                    Self::record_in_memory(child, false, witness);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// stores the in-memory nodes below `node` bottom-up, replacing each one
+    /// that is referenced by hash with a `Node::Hash`. embedded nodes stay
+    /// inline in their parent.
+    fn commit_node(db: &mut D, node: &mut Node) -> Result<(), TrieError> {
+        match node {
+            Node::Null | Node::Hash(_) => return Ok(()),
+            Node::Leaf { .. } => {}
+            Node::Extension { next, .. } => Self::commit_node(db, next)?,
+            Node::Branch { children, .. } => {
+                for child in children.iter_mut() {
+                    Self::commit_node(db, child)?;
+                }
+            }
+        }
+
+        let encoded = rlp::encode(&*node);
+        if encoded.len() >= 32 {
+            let hash = keccak256(&encoded);
+            db.put(hash, encoded.to_vec())?;
+            *node = Node::Hash(hash);
+        }
+        Ok(())
+    }
+
+    /// inserts `value` under `nibbles` below `node` and returns the value it
+    /// replaced. the nodes on the path are rewritten in place rather than
+    /// copied, and the trie is left unchanged if loading a node fails.
+    fn insert_at(&self, node: &mut Node, nibbles: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>, TrieError> {
+        let previous = match node {
             Node::Null => {
-                Node::Leaf {
-                    key: nibbles.to_vec(),
-                    value,
+                *node = Node::leaf(nibbles.to_vec(), value);
+                return Ok(None);
+            }
+            Node::Hash(hash) => {
+                *node = self.resolve(hash)?;
+                return self.insert_at(node, nibbles, value);
+            }
+            Node::Branch { children, value: branch_value, .. } => {
+                if nibbles.is_empty() {
+                    branch_value.replace(value)
+                } else {
+                    self.insert_at(&mut children[nibbles[0] as usize], &nibbles[1..], value)?
+                }
+            }
+            Node::Extension { prefix, next, .. } if nibbles.starts_with(prefix) => {
+                self.insert_at(next, &nibbles[prefix.len()..], value)?
+            }
+            Node::Leaf { key, value: leaf_value, .. } if key.as_slice() == nibbles => {
+                Some(std::mem::replace(leaf_value, value))
+            }
+            Node::Leaf { .. } | Node::Extension { .. } => {
+                *node = Self::split(std::mem::take(node), nibbles, value);
+                return Ok(None);
+            }
+        };
+
+        node.mark_dirty();
+        Ok(previous)
+    }
+
+    /// applies `ops`, sorted by path, to `node`, which sits `at` nibbles
+    /// down every path, and returns whether any entry changed. each child is
+    /// visited once with the ops below it, and a node whose ops changed
+    /// nothing, such as removals of absent keys, keeps its cached hash. when
+    /// `undo` is given, the prior value of every changed key is pushed to it.
+    fn apply_at(
+        &self,
+        node: &mut Node,
+        ops: &mut [BatchOp],
+        at: usize,
+        undo: &mut Option<Vec<UndoEntry>>,
+    ) -> Result<bool, TrieError> {
+        match ops {
+            [] => return Ok(false),
+            [(path, value)] => {
+                let (changed, previous) = match value.take() {
+                    Some(value) => (true, self.insert_at(node, &path[at..], value)?),
+                    None => {
+                        let removed = self.remove_at(node, &path[at..])?;
+                        (removed.is_some(), removed)
+                    }
+                };
+                if changed && let Some(undo) = undo {
+                    undo.push((nibbles_to_bytes(path), previous));
+                }
+                return Ok(changed);
+            }
+            _ => {}
+        }
+
+        let changed = match node {
+            Node::Hash(hash) => {
+                *node = self.resolve(hash)?;
+                return self.apply_at(node, ops, at, undo);
+            }
+            Node::Extension { prefix, next, .. } if ops.iter().all(|(path, _)| path[at..].starts_with(prefix)) => {
+                let below = at + prefix.len();
+                self.apply_at(next, ops, below, undo)?
+            }
+            Node::Branch { children, value, .. } => {
+                let mut changed = false;
+                let mut start = 0;
+                // sorting puts the one path ending here first.
+                if ops[0].0.len() == at {
+                    let (path, new) = &mut ops[0];
+                    if value.is_some() || new.is_some() {
+                        let previous = std::mem::replace(value, new.take());
+                        if let Some(undo) = undo {
+                            undo.push((nibbles_to_bytes(path), previous));
+                        }
+                        changed = true;
+                    }
+                    start = 1;
+                }
+                while start < ops.len() {
+                    let nibble = ops[start].0[at];
+                    let end = ops[start..]
+                        .iter()
+                        .position(|(path, _)| path[at] != nibble)
+                        .map_or(ops.len(), |len| start + len);
+                    changed |= self.apply_at(&mut children[nibble as usize], &mut ops[start..end], at + 1, undo)?;
+                    start = end;
                 }
+                changed
+            }
+            Node::Null | Node::Leaf { .. } | Node::Extension { .. } => {
+                // spread the node over a branch so the ops can be split by
+                // nibble. the branch normalizes itself when something
+                // changed; otherwise the original node goes back, keeping
+                // its cached hash and leaving any hashed child unloaded.
+                let mut original = std::mem::take(node);
+                let spread = match &mut original {
+                    Node::Leaf { key, value, .. } => Node::leaf(key.clone(), value.clone()),
+                    Node::Extension { prefix, next, .. } => Node::extension(prefix.clone(), std::mem::take(next)),
+                    _ => Node::Null,
+                };
+                *node = Self::expand(spread);
+                let changed = self.apply_at(node, ops, at, undo)?;
+                if !changed {
+                    Self::restore_next(&mut original, node);
+                    *node = original;
+                }
+                return Ok(changed);
+            }
+        };
+
+        if changed {
+            node.mark_dirty();
+            self.normalize(node)?;
+        }
+        Ok(changed)
+    }
+
+    /// the same entries as `node`, a null, leaf or extension, held by a
+    /// branch one nibble up.
+    fn expand(node: Node) -> Node {
+        let mut children: [Box<Node>; 16] = Default::default();
+        match node {
+            Node::Leaf { key, value, .. } if key.is_empty() => return Node::branch(children, Some(value)),
+            Node::Leaf { key, value, .. } => *children[key[0] as usize] = Node::leaf(key[1..].to_vec(), value),
+            Node::Extension { prefix, next, .. } if prefix.len() == 1 => *children[prefix[0] as usize] = *next,
+            Node::Extension { prefix, next, .. } => {
+                *children[prefix[0] as usize] = Node::extension(prefix[1..].to_vec(), next);
             }
+            _ => {}
+        }
+        Node::branch(children, None)
+    }
+
+    /// moves the child that `expand` took from the extension `original`
+    /// back out of `branch`.
+    fn restore_next(original: &mut Node, branch: &mut Node) {
+        if let Node::Extension { prefix, next, .. } = original
+            && let Node::Branch { children, .. } = branch
+        {
+            **next = match std::mem::take(&mut *children[prefix[0] as usize]) {
+                Node::Extension { next: below, .. } if prefix.len() > 1 => *below,
+                child => child,
+            };
+        }
+    }
+
+    /// replaces a leaf, or an extension whose prefix `nibbles` leaves early,
+    /// with the nodes holding both its entries and `value`.
+    fn split(node: Node, nibbles: &[u8], value: Vec<u8>) -> Node {
+        match node {
             Node::Leaf {
                 key: leaf_key,
                 value: leaf_value,
+                ..
             } => {
                 let common_len = Self::common_prefix_len(&leaf_key, nibbles);
 
                 if common_len == leaf_key.len() && common_len == nibbles.len() {
-                    Node::Leaf {
-                        key: leaf_key,
-                        value,
-                    }
+                    Node::leaf(leaf_key, value)
                 } else if common_len == 0 {
                     let mut children: [Box<Node>; 16] = [
                         Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
@@ -101,38 +650,19 @@ impl EthTrie {
                     ];
 
                     if leaf_key.is_empty() {
-                        children[nibbles[0] as usize] = Box::new(Node::Leaf {
-                            key: nibbles[1..].to_vec(),
-                            value,
-                        });
-                        return Node::Branch {
-                            children,
-                            value: Some(leaf_value),
-                        };
+                        *children[nibbles[0] as usize] = Node::leaf(nibbles[1..].to_vec(), value);
+                        return Node::branch(children, Some(leaf_value));
                     } else {
-                        children[leaf_key[0] as usize] = Box::new(Node::Leaf {
-                            key: leaf_key[1..].to_vec(),
-                            value: leaf_value,
-                        });
+                        *children[leaf_key[0] as usize] = Node::leaf(leaf_key[1..].to_vec(), leaf_value);
                     }
 
                     if nibbles.is_empty() {
-                        return Node::Branch {
-                            children,
-                            value: Some(value),
-                        };
+                        return Node::branch(children, Some(value));
                     } else {
-                        children[nibbles[0] as usize] = Box::new(Self::insert_at(
-                            *children[nibbles[0] as usize].clone(),
-                            &nibbles[1..],
-                            value,
-                        ));
+                        *children[nibbles[0] as usize] = Node::leaf(nibbles[1..].to_vec(), value);
                     }
 
-                    Node::Branch {
-                        children,
-                        value: None,
-                    }
+                    Node::branch(children, None)
                 } else {
                     let shared = nibbles[..common_len].to_vec();
                     let leaf_remainder = &leaf_key[common_len..];
@@ -147,268 +677,292 @@ impl EthTrie {
 
                     if leaf_remainder.is_empty() {
                         if nibbles_remainder.is_empty() {
-                            return Node::Leaf {
-                                key: shared,
-                                value,
-                            };
+                            return Node::leaf(shared, value);
                         }
-                        children[nibbles_remainder[0] as usize] = Box::new(Node::Leaf {
-                            key: nibbles_remainder[1..].to_vec(),
-                            value,
-                        });
-                        let branch = Node::Branch {
-                            children,
-                            value: Some(leaf_value),
-                        };
+                        *children[nibbles_remainder[0] as usize] = Node::leaf(nibbles_remainder[1..].to_vec(), value);
+                        let branch = Node::branch(children, Some(leaf_value));
                         
                         if common_len > 0 {
-                            return Node::Extension {
-                                prefix: shared,
-                                next: Box::new(branch),
-                            };
+                            return Node::extension(shared, Box::new(branch));
                         }
                         return branch;
                     } else {
-                        children[leaf_remainder[0] as usize] = Box::new(Node::Leaf {
-                            key: leaf_remainder[1..].to_vec(),
-                            value: leaf_value,
-                        });
+                        *children[leaf_remainder[0] as usize] = Node::leaf(leaf_remainder[1..].to_vec(), leaf_value);
                     }
 
                     if nibbles_remainder.is_empty() {
-                        let branch = Node::Branch {
-                            children,
-                            value: Some(value),
-                        };
+                        let branch = Node::branch(children, Some(value));
                         
                         if common_len > 0 {
-                            return Node::Extension {
-                                prefix: shared,
-                                next: Box::new(branch),
-                            };
+                            return Node::extension(shared, Box::new(branch));
                         }
                         return branch;
                     } else {
-                        children[nibbles_remainder[0] as usize] = Box::new(Node::Leaf {
-                            key: nibbles_remainder[1..].to_vec(),
-                            value,
-                        });
+                        *children[nibbles_remainder[0] as usize] = Node::leaf(nibbles_remainder[1..].to_vec(), value);
                     }
 
-                    let branch = Node::Branch {
-                        children,
-                        value: None,
-                    };
+                    let branch = Node::branch(children, None);
 
                     if common_len > 0 {
-                        Node::Extension {
-                            prefix: shared,
-                            next: Box::new(branch),
-                        }
+                        Node::extension(shared, Box::new(branch))
                     } else {
                         branch
                     }
                 }
             }
-            Node::Extension { prefix, next } => {
+            Node::Extension { prefix, next, .. } => {
                 let common_len = Self::common_prefix_len(&prefix, nibbles);
 
-                if common_len == prefix.len() {
-                    let remaining = &nibbles[common_len..];
-                    Node::Extension {
-                        prefix,
-                        next: Box::new(Self::insert_at(*next, remaining, value)),
-                    }
-                } else {
-                    let shared = prefix[..common_len].to_vec();
-                    let ext_remainder = &prefix[common_len..];
-                    let nibbles_remainder = &nibbles[common_len..];
+                let shared = prefix[..common_len].to_vec();
+                let ext_remainder = &prefix[common_len..];
+                let nibbles_remainder = &nibbles[common_len..];
 
-                    let mut children: [Box<Node>; 16] = [
-                        Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
-                        Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
-                        Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
-                        Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
-                    ];
+                let mut children: [Box<Node>; 16] = [
+                    Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
+                    Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
+                    Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
+                    Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null), Box::new(Node::Null),
+                ];
 
-                    if ext_remainder.len() == 1 {
-                        children[ext_remainder[0] as usize] = next;
-                    } else {
-                        children[ext_remainder[0] as usize] = Box::new(Node::Extension {
-                            prefix: ext_remainder[1..].to_vec(),
-                            next,
-                        });
-                    }
+                if ext_remainder.len() == 1 {
+                    children[ext_remainder[0] as usize] = next;
+                } else {
+                    *children[ext_remainder[0] as usize] = Node::extension(ext_remainder[1..].to_vec(), next);
+                }
 
-                    if nibbles_remainder.is_empty() {
-                        let branch = Node::Branch {
-                            children,
-                            value: Some(value),
-                        };
-                        
-                        if common_len > 0 {
-                            return Node::Extension {
-                                prefix: shared,
-                                next: Box::new(branch),
-                            };
-                        }
-                        return branch;
-                    } else {
-                        children[nibbles_remainder[0] as usize] = Box::new(Node::Leaf {
-                            key: nibbles_remainder[1..].to_vec(),
-                            value,
-                        });
+                if nibbles_remainder.is_empty() {
+                    let branch = Node::branch(children, Some(value));
+                    
+                    if common_len > 0 {
+                        return Node::extension(shared, Box::new(branch));
                     }
+                    return branch;
+                } else {
+                    *children[nibbles_remainder[0] as usize] = Node::leaf(nibbles_remainder[1..].to_vec(), value);
+                }
 
-                    let branch = Node::Branch {
-                        children,
-                        value: None,
-                    };
+                let branch = Node::branch(children, None);
 
-                    if common_len > 0 {
-                        Node::Extension {
-                            prefix: shared,
-                            next: Box::new(branch),
-                        }
-                    } else {
-                        branch
-                    }
+                if common_len > 0 {
+                    Node::extension(shared, Box::new(branch))
+                } else {
+                    branch
+                }
+            }
+            other => unreachable!("only leaves and extensions are split, got {:?}", other),
+        }
+    }
+
+    /// removes the value stored under `nibbles` below `node`, collapsing
+    /// every node on the way back up so the structure stays canonical.
+    fn remove_at(&self, node: &mut Node, nibbles: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        let removed = match node {
+            Node::Null => return Ok(None),
+            Node::Leaf { key, .. } => {
+                if key.as_slice() != nibbles {
+                    return Ok(None);
+                }
+                match std::mem::take(node) {
+                    Node::Leaf { value, .. } => return Ok(Some(value)),
+                    _ => unreachable!(),
+                }
+            }
+            Node::Extension { prefix, next, .. } => {
+                if !nibbles.starts_with(prefix) {
+                    return Ok(None);
                 }
+                self.remove_at(next, &nibbles[prefix.len()..])?
             }
-            Node::Branch { mut children, value: branch_value } => {
+            Node::Branch { children, value, .. } => {
                 if nibbles.is_empty() {
-                    Node::Branch {
-                        children,
-                        value: Some(value),
-                    }
+                    value.take()
                 } else {
-                    let idx = nibbles[0] as usize;
-                    children[idx] = Box::new(Self::insert_at(
-                        *children[idx].clone(),
-                        &nibbles[1..],
-                        value,
-                    ));
-                    Node::Branch {
-                        children,
-                        value: branch_value,
+                    self.remove_at(&mut children[nibbles[0] as usize], &nibbles[1..])?
+                }
+            }
+            Node::Hash(hash) => {
+                *node = self.resolve(hash)?;
+                return self.remove_at(node, nibbles);
+            }
+        };
+
+        if removed.is_some() {
+            node.mark_dirty();
+            self.normalize(node)?;
+        }
+        Ok(removed)
+    }
+
+    /// rewrites a branch or extension that may have lost a child or its
+    /// value into the canonical node for the keys it still holds.
+    fn normalize(&self, node: &mut Node) -> Result<(), TrieError> {
+        match node {
+            Node::Extension { next, .. } => {
+                if matches!(**next, Node::Null | Node::Leaf { .. } | Node::Extension { .. })
+                    && let Node::Extension { prefix, next, .. } = std::mem::take(node)
+                {
+                    *node = Self::join_prefix(prefix, *next);
+                }
+            }
+            Node::Branch { children, value, .. } => {
+                let mut occupied = children
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, child)| !matches!(***child, Node::Null))
+                    .map(|(i, _)| i);
+
+                match (occupied.next(), occupied.next(), value.is_some()) {
+                    (None, _, false) => *node = Node::Null,
+                    (None, _, true) => {
+                        *node = Node::leaf(Vec::new(), value.take().unwrap_or_default());
+                    }
+                    (Some(idx), None, false) => {
+                        // the remaining child has to be loaded to know whether
+                        // its path can absorb the branch nibble.
+                        if let Node::Hash(hash) = *children[idx] {
+                            *children[idx] = self.resolve(&hash)?;
+                        }
+                        let child = std::mem::take(&mut *children[idx]);
+                        *node = Self::join_prefix(vec![idx as u8], child);
                     }
+                    _ => {}
                 }
             }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// prepends `prefix` to the path of `node`, merging it into a leaf or
+    /// extension where possible instead of stacking extensions.
+    fn join_prefix(mut prefix: Vec<u8>, node: Node) -> Node {
+        match node {
+            Node::Null => Node::Null,
+            Node::Leaf { key, value, .. } => {
+                prefix.extend_from_slice(&key);
+                Node::leaf(prefix, value)
+            }
+            Node::Extension {
+                prefix: ext_prefix,
+                next,
+                ..
+            } => {
+                prefix.extend_from_slice(&ext_prefix);
+                Node::extension(prefix, next)
+            }
+            node @ (Node::Branch { .. } | Node::Hash(_)) => Node::extension(prefix, Box::new(node)),
         }
     }
 
-    fn get_at(node: &Node, nibbles: &[u8]) -> Option<Vec<u8>> {
+    fn get_at(&self, node: &Node, nibbles: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
         match node {
-            Node::Null => None,
-            Node::Leaf { key, value } => {
+            Node::Null => Ok(None),
+            Node::Leaf { key, value, .. } => {
                 if key == nibbles {
-                    Some(value.clone())
+                    Ok(Some(value.clone()))
                 } else {
-                    None
+                    Ok(None)
                 }
             }
-            Node::Extension { prefix, next } => {
+            Node::Extension { prefix, next, .. } => {
                 if nibbles.len() < prefix.len() {
-                    return None;
+                    return Ok(None);
                 }
                 if &nibbles[..prefix.len()] == prefix.as_slice() {
-                    Self::get_at(next, &nibbles[prefix.len()..])
+                    self.get_at(next, &nibbles[prefix.len()..])
                 } else {
-                    None
+                    Ok(None)
                 }
             }
-            Node::Branch { children, value } => {
+            Node::Branch { children, value, .. } => {
                 if nibbles.is_empty() {
-                    value.clone()
+                    Ok(value.clone())
                 } else {
                     let idx = nibbles[0] as usize;
-                    Self::get_at(&children[idx], &nibbles[1..])
+                    self.get_at(&children[idx], &nibbles[1..])
                 }
             }
+            Node::Hash(hash) => self.get_at(&self.resolve(hash)?, nibbles),
         }
     }
 
-    fn get_proof_at(node: &Node, nibbles: &[u8], proof: &mut Vec<Vec<u8>>) {
+    fn get_proof_at(&self, node: &Node, nibbles: &[u8], proof: &mut Vec<Vec<u8>>) -> Result<(), TrieError> {
+        if let Node::Hash(hash) = node {
+            return self.get_proof_at(&self.resolve(hash)?, nibbles, proof);
+        }
+
         let encoded = rlp::encode(node);
         proof.push(encoded.to_vec());
 
         match node {
-            Node::Null => {
-            }
-            Node::Leaf { key: _, value: _ } => {
-            }
-            Node::Extension { prefix, next } => {
+            Node::Null | Node::Leaf { .. } | Node::Hash(_) => Ok(()),
+            Node::Extension { prefix, next, .. } => {
                 if nibbles.len() >= prefix.len() && &nibbles[..prefix.len()] == prefix.as_slice() {
-                    Self::get_proof_at(next, &nibbles[prefix.len()..], proof);
+                    self.get_proof_at(next, &nibbles[prefix.len()..], proof)?;
                 }
+                Ok(())
             }
-            Node::Branch { children, value: _ } => {
+            Node::Branch { children, .. } => {
                 if !nibbles.is_empty() {
                     let idx = nibbles[0] as usize;
-                    Self::get_proof_at(&children[idx], &nibbles[1..], proof);
+                    self.get_proof_at(&children[idx], &nibbles[1..], proof)?;
                 }
+                Ok(())
             }
         }
     }
 
-    fn verify_proof_recursive(
-        nibbles: &[u8],
-        proof: &[Vec<u8>],
-        proof_index: usize,
-    ) -> Option<Vec<u8>> {
-        if proof_index >= proof.len() {
-            return None;
+    /// walks the sorted `paths` below `node` together, so a node shared by
+    /// several paths is resolved and encoded once. `hashed` is set for nodes
+    /// referenced by hash, which the root always is.
+    fn multiproof_at(
+        &self,
+        node: &Node,
+        paths: &[Vec<u8>],
+        hashed: bool,
+        seen: &mut HashSet<[u8; 32]>,
+        proof: &mut Vec<Vec<u8>>,
+    ) -> Result<(), TrieError> {
+        if let Node::Hash(hash) = node {
+            return self.multiproof_at(&self.resolve(hash)?, paths, true, seen, proof);
         }
 
-        let current_item = &proof[proof_index];
-        
-        let node: Node = match rlp::decode(current_item) {
-            Ok(n) => n,
-            Err(_) => return None,
-        };
-
-        match node {
-            Node::Null => None,
-            Node::Leaf { key, value } => {
-                if key == nibbles {
-                    Some(value)
-                } else {
-                    None
-                }
+        if hashed || node.reference().len() == 32 {
+            let encoded = rlp::encode(node).to_vec();
+            if seen.insert(keccak256(&encoded)) {
+                proof.push(encoded);
             }
-            Node::Extension { prefix, next: _ } => {
-                if nibbles.len() < prefix.len() || &nibbles[..prefix.len()] != prefix.as_slice() {
-                    return None;
-                }
+        }
 
-                if proof_index + 1 >= proof.len() {
-                    return None;
+        match node {
+            Node::Null | Node::Leaf { .. } | Node::Hash(_) => Ok(()),
+            Node::Extension { prefix, next, .. } => {
+                let below: Vec<Vec<u8>> = paths
+                    .iter()
+                    .filter_map(|path| path.strip_prefix(prefix.as_slice()).map(<[u8]>::to_vec))
+                    .collect();
+                if below.is_empty() {
+                    return Ok(());
                 }
-
-                Self::verify_proof_recursive(&nibbles[prefix.len()..], proof, proof_index + 1)
+                self.multiproof_at(next, &below, false, seen, proof)
             }
-            Node::Branch { children: _, value } => {
-                if nibbles.is_empty() {
-                    value
-                } else {
-                    if proof_index + 1 >= proof.len() {
-                        return None;
+            Node::Branch { children, .. } => {
+                // a path ending here is answered by the branch value; the
+                // rest are sorted, so each child's paths are contiguous.
+                let mut rest = paths.iter().skip_while(|path| path.is_empty()).peekable();
+                while let Some(first) = rest.peek() {
+                    let nibble = first[0];
+                    let mut below = Vec::new();
+                    while let Some(path) = rest.next_if(|path| path[0] == nibble) {
+                        below.push(path[1..].to_vec());
                     }
-
-                    Self::verify_proof_recursive(&nibbles[1..], proof, proof_index + 1)
+                    self.multiproof_at(&children[nibble as usize], &below, false, seen, proof)?;
                 }
+                Ok(())
             }
         }
     }
 
-    fn compute_hash(data: &[u8]) -> [u8; 32] {
-        let mut hasher = Keccak::v256();
-        let mut output = [0u8; 32];
-        hasher.update(data);
-        hasher.finalize(&mut output);
-        output
-    }
-
     fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
         let mut len = 0;
         let min_len = a.len().min(b.len());
@@ -422,14 +976,14 @@ impl EthTrie {
         len
     }
 
-    fn print_node(node: &Node, depth: usize, prefix: &str) {
+    fn print_node(&self, node: &Node, depth: usize, prefix: &str) {
         let indent = "  ".repeat(depth);
         
         match node {
             Node::Null => {
                 println!("{}{}[null]", indent, prefix);
             }
-            Node::Leaf { key, value } => {
+            Node::Leaf { key, value, .. } => {
                 println!(
                     "{}{}[leaf] path: {:?}, value: {:?}",
                     indent,
@@ -438,16 +992,16 @@ impl EthTrie {
                     String::from_utf8_lossy(value)
                 );
             }
-            Node::Extension { prefix: ext_prefix, next } => {
+            Node::Extension { prefix: ext_prefix, next, .. } => {
                 println!(
                     "{}{}[extension] prefix: {:?}",
                     indent,
                     prefix,
                     Self::format_nibbles(ext_prefix)
                 );
-                Self::print_node(next, depth + 1, "└─ ");
+                self.print_node(next, depth + 1, "└─ ");
             }
-            Node::Branch { children, value } => {
+            Node::Branch { children, value, .. } => {
                 if let Some(v) = value {
                     println!(
                         "{}{}[branch] value: {:?}",
@@ -462,10 +1016,14 @@ impl EthTrie {
                 for (i, child) in children.iter().enumerate() {
                     if !matches!(**child, Node::Null) {
                         let child_prefix = format!("[{:x}] ", i);
-                        Self::print_node(child, depth + 1, &child_prefix);
+                        self.print_node(child, depth + 1, &child_prefix);
                     }
                 }
             }
+            Node::Hash(hash) => match self.resolve(hash) {
+                Ok(resolved) => self.print_node(&resolved, depth, prefix),
+                Err(err) => println!("{}{}[hash] {} ({})", indent, prefix, hex::encode(hash), err),
+            },
         }
     }
 
@@ -484,7 +1042,31 @@ impl Default for EthTrie {
     }
 }
 
+/// the keccak hash of `i`, a key spread evenly over the trie.
+#[cfg(test)]
+pub(crate) fn hashed_key(i: u32) -> Vec<u8> {
+    keccak256(&i.to_be_bytes()).to_vec()
+}
+
+/// a value long enough that a leaf holding it is stored by hash.
+#[cfg(test)]
+pub(crate) fn long_value(tag: &str) -> Vec<u8> {
+    format!("{}-{}", tag, "x".repeat(40)).into_bytes()
+}
+
+/// an uncommitted trie mapping `hashed_key(i)` to "value-i" for each `i`
+/// below `count`.
+#[cfg(test)]
+pub(crate) fn hashed_trie(count: u32) -> EthTrie {
+    let mut trie = EthTrie::new();
+    for i in 0..count {
+        trie.insert(&hashed_key(i), format!("value-{}", i).as_bytes()).unwrap();
+    }
+    trie
+}
+
 #[cfg(test)]
+#[allow(clippy::explicit_auto_deref, clippy::useless_vec)]
 mod tests {
     use super::*;
 
@@ -530,7 +1112,7 @@ mod tests {
         let mut trie = EthTrie::new();
         let initial_hash = trie.root_hash();
         
-        trie.insert(b"test", b"value");
+        trie.insert(b"test", b"value").unwrap();
         let after_insert_hash = trie.root_hash();
         
         assert_ne!(initial_hash, after_insert_hash, "root hash must change after insert");
@@ -540,8 +1122,8 @@ mod tests {
     fn test_insert_and_get_single_key() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"test", b"value");
-        let result = trie.get(b"test");
+        trie.insert(b"test", b"value").unwrap();
+        let result = trie.get(b"test").unwrap();
         
         assert_eq!(result, Some(b"value".to_vec()));
     }
@@ -550,8 +1132,8 @@ mod tests {
     fn test_get_nonexistent_key() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"test", b"value");
-        let result = trie.get(b"other");
+        trie.insert(b"test", b"value").unwrap();
+        let result = trie.get(b"other").unwrap();
         
         assert_eq!(result, None);
     }
@@ -560,43 +1142,43 @@ mod tests {
     fn test_insert_dog_then_do() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"dog", b"puppy");
+        trie.insert(b"dog", b"puppy").unwrap();
         let hash_after_dog = trie.root_hash();
         println!("hash after inserting 'dog': {}", hex::encode(hash_after_dog));
         
-        trie.insert(b"do", b"verb");
+        trie.insert(b"do", b"verb").unwrap();
         let hash_after_do = trie.root_hash();
         println!("hash after inserting 'do': {}", hex::encode(hash_after_do));
         
         assert_ne!(hash_after_dog, hash_after_do, "root hash must change after inserting 'do'");
         
-        assert_eq!(trie.get(b"dog"), Some(b"puppy".to_vec()));
-        assert_eq!(trie.get(b"do"), Some(b"verb".to_vec()));
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+        assert_eq!(trie.get(b"do").unwrap(), Some(b"verb".to_vec()));
     }
 
     #[test]
     fn test_update_existing_key() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"key", b"value1");
-        assert_eq!(trie.get(b"key"), Some(b"value1".to_vec()));
+        trie.insert(b"key", b"value1").unwrap();
+        assert_eq!(trie.get(b"key").unwrap(), Some(b"value1".to_vec()));
         
-        trie.insert(b"key", b"value2");
-        assert_eq!(trie.get(b"key"), Some(b"value2".to_vec()));
+        trie.insert(b"key", b"value2").unwrap();
+        assert_eq!(trie.get(b"key").unwrap(), Some(b"value2".to_vec()));
     }
 
     #[test]
     fn test_insert_with_shared_prefix() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"test", b"value1");
-        trie.insert(b"testing", b"value2");
-        trie.insert(b"tea", b"value3");
+        trie.insert(b"test", b"value1").unwrap();
+        trie.insert(b"testing", b"value2").unwrap();
+        trie.insert(b"tea", b"value3").unwrap();
         
-        assert_eq!(trie.get(b"test"), Some(b"value1".to_vec()));
-        assert_eq!(trie.get(b"testing"), Some(b"value2".to_vec()));
-        assert_eq!(trie.get(b"tea"), Some(b"value3".to_vec()));
-        assert_eq!(trie.get(b"te"), None);
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(trie.get(b"testing").unwrap(), Some(b"value2".to_vec()));
+        assert_eq!(trie.get(b"tea").unwrap(), Some(b"value3".to_vec()));
+        assert_eq!(trie.get(b"te").unwrap(), None);
     }
 
     #[test]
@@ -607,11 +1189,11 @@ mod tests {
         let values = vec![b"1", b"2", b"3", b"4", b"5"];
         
         for (key, value) in keys.iter().zip(values.iter()) {
-            trie.insert(*key, *value);
+            trie.insert(*key, *value).unwrap();
         }
         
         for (key, value) in keys.iter().zip(values.iter()) {
-            assert_eq!(trie.get(*key), Some((*value).to_vec()));
+            assert_eq!(trie.get(*key).unwrap(), Some((*value).to_vec()));
         }
     }
 
@@ -625,12 +1207,12 @@ mod tests {
             let key = format!("key_{}", i);
             let value = format!("value_{}", i);
             
-            trie.insert(key.as_bytes(), value.as_bytes());
+            trie.insert(key.as_bytes(), value.as_bytes()).unwrap();
             expected.insert(key, value);
         }
         
         for (key, value) in expected.iter() {
-            let result = trie.get(key.as_bytes());
+            let result = trie.get(key.as_bytes()).unwrap();
             assert_eq!(
                 result,
                 Some(value.as_bytes().to_vec()),
@@ -644,35 +1226,35 @@ mod tests {
     fn test_insert_empty_key() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"", b"empty_key_value");
-        trie.insert(b"a", b"value_a");
+        trie.insert(b"", b"empty_key_value").unwrap();
+        trie.insert(b"a", b"value_a").unwrap();
         
-        assert_eq!(trie.get(b""), Some(b"empty_key_value".to_vec()));
-        assert_eq!(trie.get(b"a"), Some(b"value_a".to_vec()));
+        assert_eq!(trie.get(b"").unwrap(), Some(b"empty_key_value".to_vec()));
+        assert_eq!(trie.get(b"a").unwrap(), Some(b"value_a".to_vec()));
     }
 
     #[test]
     fn test_complex_branching() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"do", b"verb");
-        trie.insert(b"dog", b"puppy");
-        trie.insert(b"doge", b"coin");
-        trie.insert(b"horse", b"stallion");
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
         
-        assert_eq!(trie.get(b"do"), Some(b"verb".to_vec()));
-        assert_eq!(trie.get(b"dog"), Some(b"puppy".to_vec()));
-        assert_eq!(trie.get(b"doge"), Some(b"coin".to_vec()));
-        assert_eq!(trie.get(b"horse"), Some(b"stallion".to_vec()));
-        assert_eq!(trie.get(b"d"), None);
+        assert_eq!(trie.get(b"do").unwrap(), Some(b"verb".to_vec()));
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+        assert_eq!(trie.get(b"doge").unwrap(), Some(b"coin".to_vec()));
+        assert_eq!(trie.get(b"horse").unwrap(), Some(b"stallion".to_vec()));
+        assert_eq!(trie.get(b"d").unwrap(), None);
     }
 
     #[test]
     fn test_get_proof_single_key() {
         let mut trie = EthTrie::new();
-        trie.insert(b"test", b"value");
+        trie.insert(b"test", b"value").unwrap();
         
-        let proof = trie.get_proof(b"test");
+        let proof = trie.get_proof(b"test").unwrap();
         
         assert!(!proof.is_empty(), "proof should not be empty");
     }
@@ -680,13 +1262,13 @@ mod tests {
     #[test]
     fn test_get_proof_multiple_keys() {
         let mut trie = EthTrie::new();
-        trie.insert(b"do", b"verb");
-        trie.insert(b"dog", b"puppy");
-        trie.insert(b"doge", b"coin");
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
         
-        let proof_do = trie.get_proof(b"do");
-        let proof_dog = trie.get_proof(b"dog");
-        let proof_doge = trie.get_proof(b"doge");
+        let proof_do = trie.get_proof(b"do").unwrap();
+        let proof_dog = trie.get_proof(b"dog").unwrap();
+        let proof_doge = trie.get_proof(b"doge").unwrap();
         
         assert!(!proof_do.is_empty());
         assert!(!proof_dog.is_empty());
@@ -698,50 +1280,54 @@ mod tests {
     #[test]
     fn test_verify_proof_valid() {
         let mut trie = EthTrie::new();
-        trie.insert(b"test", b"value");
+        trie.insert(b"test", b"value").unwrap();
         
         let root_hash = trie.root_hash();
-        let proof = trie.get_proof(b"test");
+        let proof = trie.get_proof(b"test").unwrap();
         
         let result = EthTrie::verify_proof(&root_hash, b"test", &proof);
         
-        assert_eq!(result, Some(b"value".to_vec()), "valid proof should return the value");
+        assert_eq!(result, Ok(Some(b"value".to_vec())), "valid proof should return the value");
     }
 
     #[test]
     fn test_verify_proof_invalid_key() {
         let mut trie = EthTrie::new();
-        trie.insert(b"test", b"value");
+        trie.insert(b"test", b"value").unwrap();
         
         let root_hash = trie.root_hash();
-        let proof = trie.get_proof(b"test");
+        let proof = trie.get_proof(b"test").unwrap();
         
         let result = EthTrie::verify_proof(&root_hash, b"other", &proof);
         
-        assert_eq!(result, None, "invalid key should return None");
+        assert_eq!(result, Ok(None), "proof for another key should prove absence");
     }
 
     #[test]
     fn test_verify_proof_wrong_root_hash() {
         let mut trie = EthTrie::new();
-        trie.insert(b"test", b"value");
+        trie.insert(b"test", b"value").unwrap();
         
-        let proof = trie.get_proof(b"test");
+        let proof = trie.get_proof(b"test").unwrap();
         
         let wrong_hash = [0u8; 32];
         let result = EthTrie::verify_proof(&wrong_hash, b"test", &proof);
         
-        assert_eq!(result, None, "wrong root hash should return None");
+        assert_eq!(
+            result,
+            Err(ProofError::MissingNode { hash: wrong_hash, depth: 0 }),
+            "wrong root hash should be rejected"
+        );
     }
 
     #[test]
     fn test_verify_proof_complex_trie() {
         let mut trie = EthTrie::new();
         
-        trie.insert(b"do", b"verb");
-        trie.insert(b"dog", b"puppy");
-        trie.insert(b"doge", b"coin");
-        trie.insert(b"horse", b"stallion");
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
         
         let root_hash = trie.root_hash();
         
@@ -753,12 +1339,12 @@ mod tests {
         ];
         
         for (key, expected_value) in test_cases {
-            let proof = trie.get_proof(*key);
+            let proof = trie.get_proof(*key).unwrap();
             let result = EthTrie::verify_proof(&root_hash, *key, &proof);
             
             assert_eq!(
                 result,
-                Some(expected_value.to_vec()),
+                Ok(Some(expected_value.to_vec())),
                 "proof verification failed for key: {:?}",
                 String::from_utf8_lossy(*key)
             );
@@ -768,15 +1354,15 @@ mod tests {
     #[test]
     fn test_proof_for_nonexistent_key() {
         let mut trie = EthTrie::new();
-        trie.insert(b"test", b"value");
+        trie.insert(b"test", b"value").unwrap();
         
         let root_hash = trie.root_hash();
-        let proof = trie.get_proof(b"other");
+        let proof = trie.get_proof(b"other").unwrap();
         
         assert!(!proof.is_empty());
         
         let result = EthTrie::verify_proof(&root_hash, b"other", &proof);
-        assert_eq!(result, None, "nonexistent key should return None");
+        assert_eq!(result, Ok(None), "nonexistent key should be proven absent");
     }
 
     #[test]
@@ -785,7 +1371,7 @@ mod tests {
         let root_hash = trie.root_hash();
         
         let result = EthTrie::verify_proof(&root_hash, b"test", &[]);
-        assert_eq!(result, None);
+        assert_eq!(result, Ok(None), "every key is absent from the empty trie");
     }
 
     #[test]
@@ -795,7 +1381,7 @@ mod tests {
         for i in 0..10 {
             let key = format!("key{}", i);
             let value = format!("value{}", i);
-            trie.insert(key.as_bytes(), value.as_bytes());
+            trie.insert(key.as_bytes(), value.as_bytes()).unwrap();
         }
         
         let root_hash = trie.root_hash();
@@ -804,12 +1390,12 @@ mod tests {
             let key = format!("key{}", i);
             let expected_value = format!("value{}", i);
             
-            let proof = trie.get_proof(key.as_bytes());
+            let proof = trie.get_proof(key.as_bytes()).unwrap();
             let result = EthTrie::verify_proof(&root_hash, key.as_bytes(), &proof);
             
             assert_eq!(
                 result,
-                Some(expected_value.as_bytes().to_vec()),
+                Ok(Some(expected_value.as_bytes().to_vec())),
                 "round trip failed for key: {}",
                 key
             );
@@ -822,8 +1408,8 @@ mod tests {
         
         trie.print_tree();
         
-        trie.insert(b"test", b"value");
-        trie.insert(b"testing", b"another");
+        trie.insert(b"test", b"value").unwrap();
+        trie.insert(b"testing", b"another").unwrap();
         
         trie.print_tree();
     }
@@ -831,21 +1417,21 @@ mod tests {
     #[test]
     fn test_insertion_order_determinism() {
         let mut trie1 = EthTrie::new();
-        trie1.insert(b"alice", b"100");
-        trie1.insert(b"bob", b"200");
-        trie1.insert(b"charlie", b"300");
+        trie1.insert(b"alice", b"100").unwrap();
+        trie1.insert(b"bob", b"200").unwrap();
+        trie1.insert(b"charlie", b"300").unwrap();
         let hash1 = trie1.root_hash();
 
         let mut trie2 = EthTrie::new();
-        trie2.insert(b"charlie", b"300");
-        trie2.insert(b"alice", b"100");
-        trie2.insert(b"bob", b"200");
+        trie2.insert(b"charlie", b"300").unwrap();
+        trie2.insert(b"alice", b"100").unwrap();
+        trie2.insert(b"bob", b"200").unwrap();
         let hash2 = trie2.root_hash();
 
         let mut trie3 = EthTrie::new();
-        trie3.insert(b"bob", b"200");
-        trie3.insert(b"charlie", b"300");
-        trie3.insert(b"alice", b"100");
+        trie3.insert(b"bob", b"200").unwrap();
+        trie3.insert(b"charlie", b"300").unwrap();
+        trie3.insert(b"alice", b"100").unwrap();
         let hash3 = trie3.root_hash();
 
         assert_eq!(
@@ -863,34 +1449,971 @@ mod tests {
     #[test]
     fn test_proof_can_be_shared_and_verified() {
         let mut trie = EthTrie::new();
-        trie.insert(b"ethereum", b"blockchain");
-        trie.insert(b"bitcoin", b"cryptocurrency");
-        trie.insert(b"solana", b"fast");
+        trie.insert(b"ethereum", b"blockchain").unwrap();
+        trie.insert(b"bitcoin", b"cryptocurrency").unwrap();
+        trie.insert(b"solana", b"fast").unwrap();
 
         let root_hash = trie.root_hash();
-        let proof_for_ethereum = trie.get_proof(b"ethereum");
+        let proof_for_ethereum = trie.get_proof(b"ethereum").unwrap();
 
         let verified_value = EthTrie::verify_proof(&root_hash, b"ethereum", &proof_for_ethereum);
 
         assert_eq!(
             verified_value,
-            Some(b"blockchain".to_vec()),
+            Ok(Some(b"blockchain".to_vec())),
             "friend should be able to verify proof with just root hash"
         );
 
         let keys: &[&[u8]] = &[b"ethereum", b"bitcoin", b"solana"];
         for key in keys {
-            let proof = trie.get_proof(*key);
+            let proof = trie.get_proof(*key).unwrap();
             let result = EthTrie::verify_proof(&root_hash, *key, &proof);
             assert!(
-                result.is_some(),
+                matches!(result, Ok(Some(_))),
                 "proof for {} should be verifiable",
                 String::from_utf8_lossy(*key)
             );
         }
 
-        let fake_proof = trie.get_proof(b"nonexistent");
+        let fake_proof = trie.get_proof(b"nonexistent").unwrap();
         let fake_result = EthTrie::verify_proof(&root_hash, b"nonexistent", &fake_proof);
-        assert_eq!(fake_result, None, "non-existent key should not verify");
+        assert_eq!(fake_result, Ok(None), "non-existent key should be proven absent");
+    }
+
+    #[test]
+    fn test_remove_returns_old_value() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"test", b"value").unwrap();
+
+        assert_eq!(trie.remove(b"test").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(trie.get(b"test").unwrap(), None);
+    }
+
+    #[test]
+    fn test_remove_nonexistent_key() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"test", b"value").unwrap();
+        let hash_before = trie.root_hash();
+
+        assert_eq!(trie.remove(b"other").unwrap(), None);
+        assert_eq!(trie.remove(b"tes").unwrap(), None);
+        assert_eq!(trie.remove(b"testing").unwrap(), None);
+        assert_eq!(trie.root_hash(), hash_before, "removing a missing key must not change the root");
+    }
+
+    #[test]
+    fn test_remove_last_key_gives_empty_root() {
+        let mut trie = EthTrie::new();
+        let empty_hash = trie.root_hash();
+
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.remove(b"do").unwrap();
+        trie.remove(b"dog").unwrap();
+
+        assert_eq!(trie.root_hash(), empty_hash);
+    }
+
+    #[test]
+    fn test_remove_collapses_branch_into_leaf() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
+        trie.remove(b"horse").unwrap();
+
+        let mut expected = EthTrie::new();
+        expected.insert(b"dog", b"puppy").unwrap();
+
+        assert!(matches!(*trie.root, Node::Leaf { .. }));
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_remove_branch_value_becomes_leaf() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.remove(b"dog").unwrap();
+
+        let mut expected = EthTrie::new();
+        expected.insert(b"do", b"verb").unwrap();
+
+        assert_eq!(trie.get(b"do").unwrap(), Some(b"verb".to_vec()));
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_remove_merges_extensions() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
+        trie.remove(b"do").unwrap();
+        trie.remove(b"doge").unwrap();
+
+        let mut expected = EthTrie::new();
+        expected.insert(b"dog", b"puppy").unwrap();
+        expected.insert(b"horse", b"stallion").unwrap();
+
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_remove_matches_fresh_trie() {
+        let mut trie = EthTrie::new();
+        for i in 0..100 {
+            let key = format!("key_{}", i);
+            let value = format!("value_{}", i);
+            trie.insert(key.as_bytes(), value.as_bytes()).unwrap();
+        }
+
+        for i in (0..100).filter(|i| i % 3 != 0) {
+            let key = format!("key_{}", i);
+            let value = format!("value_{}", i);
+            assert_eq!(trie.remove(key.as_bytes()).unwrap(), Some(value.into_bytes()));
+        }
+
+        let mut expected = EthTrie::new();
+        for i in (0..100).filter(|i| i % 3 == 0) {
+            let key = format!("key_{}", i);
+            let value = format!("value_{}", i);
+            expected.insert(key.as_bytes(), value.as_bytes()).unwrap();
+        }
+
+        assert_eq!(trie.root_hash(), expected.root_hash());
+        for i in 0..100 {
+            let key = format!("key_{}", i);
+            assert_eq!(trie.get(key.as_bytes()).unwrap().is_some(), i % 3 == 0, "wrong presence for {}", key);
+        }
+    }
+
+    #[test]
+    fn test_verify_proof_rejects_forged_leaf() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"do", &long_value("verb")).unwrap();
+        trie.insert(b"dog", &long_value("puppy")).unwrap();
+        trie.insert(b"horse", &long_value("stallion")).unwrap();
+
+        let root_hash = trie.root_hash();
+        let mut proof = trie.get_proof(b"dog").unwrap();
+        assert!(matches!(EthTrie::verify_proof(&root_hash, b"dog", &proof), Ok(Some(_))));
+
+        let last = proof.pop().unwrap();
+        let forged = match rlp::decode::<Node>(&last).unwrap() {
+            Node::Leaf { key, .. } => Node::leaf(key, long_value("forged")),
+            other => panic!("expected leaf at end of proof, got {:?}", other),
+        };
+        proof.push(rlp::encode(&forged).to_vec());
+
+        assert!(
+            matches!(EthTrie::verify_proof(&root_hash, b"dog", &proof), Err(ProofError::MissingNode { .. })),
+            "a proof item that does not match its parent's reference must be rejected"
+        );
+    }
+
+    #[test]
+    fn test_verify_proof_rejects_nodes_from_other_trie() {
+        let mut trie = EthTrie::new();
+        let mut other = EthTrie::new();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value("real")).unwrap();
+            other.insert(key.as_bytes(), &long_value("fake")).unwrap();
+        }
+
+        let root_hash = trie.root_hash();
+        let mut proof = trie.get_proof(b"key7").unwrap();
+        let fake_proof = other.get_proof(b"key7").unwrap();
+        proof.truncate(1);
+        proof.extend_from_slice(&fake_proof[1..]);
+
+        assert!(matches!(
+            EthTrie::verify_proof(&root_hash, b"key7", &proof),
+            Err(ProofError::MissingNode { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_proof_missing_intermediate_node() {
+        let mut trie = EthTrie::new();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value("value")).unwrap();
+        }
+
+        let root_hash = trie.root_hash();
+        let mut proof = trie.get_proof(b"key7").unwrap();
+        assert!(proof.len() > 2);
+        proof.remove(1);
+
+        assert!(matches!(
+            EthTrie::verify_proof(&root_hash, b"key7", &proof),
+            Err(ProofError::MissingNode { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_proof_ignores_item_order_and_extra_items() {
+        let mut trie = EthTrie::new();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value(&key)).unwrap();
+        }
+
+        let root_hash = trie.root_hash();
+        let mut proof = trie.get_proof(b"key7").unwrap();
+        proof.extend(trie.get_proof(b"key12").unwrap());
+        proof.reverse();
+
+        assert_eq!(
+            EthTrie::verify_proof(&root_hash, b"key7", &proof),
+            Ok(Some(long_value("key7")))
+        );
+    }
+
+    #[test]
+    fn test_root_hash_matches_ethereum_dogs_vector() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"doe", b"reindeer").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"dogglesworth", b"cat").unwrap();
+
+        assert_eq!(
+            hex::encode(trie.root_hash()),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+    }
+
+    #[test]
+    fn test_root_hash_matches_ethereum_puppy_vector() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+
+        assert_eq!(
+            hex::encode(trie.root_hash()),
+            "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+        );
+    }
+
+    fn decoded_trie(trie: &EthTrie) -> EthTrie {
+        let encoded = rlp::encode(&*trie.root);
+        EthTrie::from_node(rlp::decode(&encoded).unwrap())
+    }
+
+    #[test]
+    fn test_decoded_root_keeps_root_hash() {
+        let mut trie = EthTrie::new();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value(&key)).unwrap();
+        }
+
+        let decoded = decoded_trie(&trie);
+        assert_eq!(decoded.root_hash(), trie.root_hash());
+    }
+
+    #[test]
+    fn test_unresolved_hash_node_reports_missing_node() {
+        let mut trie = EthTrie::new();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value(&key)).unwrap();
+        }
+
+        let mut decoded = decoded_trie(&trie);
+        let hash_before = decoded.root_hash();
+
+        assert!(matches!(decoded.get(b"key7"), Err(TrieError::MissingNode(_))));
+        assert!(matches!(decoded.get_proof(b"key7"), Err(TrieError::MissingNode(_))));
+        assert!(matches!(decoded.insert(b"key7", b"new"), Err(TrieError::MissingNode(_))));
+        assert!(matches!(decoded.remove(b"key7"), Err(TrieError::MissingNode(_))));
+        assert_eq!(decoded.root_hash(), hash_before, "failed operations must not modify the trie");
+    }
+
+    #[test]
+    fn test_insert_moves_untouched_subtrees() {
+        let mut trie = EthTrie::new();
+        for i in 0..64u8 {
+            trie.insert(&[i << 2, 0x01], b"value").unwrap();
+        }
+        let sibling = |trie: &EthTrie| match &*trie.root {
+            Node::Branch { children, .. } => &*children[0xf] as *const Node,
+            other => panic!("expected branch root, got {:?}", other),
+        };
+        let before = sibling(&trie);
+
+        // the new key goes below child 0x0; child 0xf must not be copied.
+        trie.insert(&[0x00, 0x02], b"value").unwrap();
+        assert_eq!(sibling(&trie), before);
+    }
+
+    #[test]
+    fn test_hash_root_is_its_own_root_hash() {
+        let trie = EthTrie::from_node(Node::Hash([0x11; 32]));
+
+        assert_eq!(trie.root_hash(), [0x11; 32]);
+        assert!(matches!(trie.get(b"anything"), Err(TrieError::MissingNode(hash)) if hash == [0x11; 32]));
+    }
+
+    #[test]
+    fn test_commit_returns_root_hash() {
+        let mut trie = EthTrie::new();
+        assert_eq!(trie.commit().unwrap(), EMPTY_ROOT);
+
+        for i in 0..50 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value(&key)).unwrap();
+        }
+        let expected = trie.root_hash();
+
+        assert_eq!(trie.commit().unwrap(), expected);
+        assert_eq!(trie.root_hash(), expected);
+        assert!(matches!(*trie.root, Node::Hash(hash) if hash == expected));
+        assert!(!trie.db().is_empty());
+    }
+
+    #[test]
+    fn test_committed_trie_resolves_lazily() {
+        let mut trie = EthTrie::new();
+        for i in 0..50 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value(&key)).unwrap();
+        }
+        trie.commit().unwrap();
+
+        for i in 0..50 {
+            let key = format!("key{}", i);
+            assert_eq!(trie.get(key.as_bytes()).unwrap(), Some(long_value(&key)));
+        }
+        assert_eq!(trie.get(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_reopen_from_root_and_db() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
+        let root = trie.commit().unwrap();
+
+        let mut reopened = EthTrie::from_root(root, trie.into_db());
+        assert_eq!(reopened.root_hash(), root);
+        assert_eq!(reopened.get(b"doge").unwrap(), Some(b"coin".to_vec()));
+
+        let proof = reopened.get_proof(b"dog").unwrap();
+        assert_eq!(EthTrie::verify_proof(&root, b"dog", &proof), Ok(Some(b"puppy".to_vec())));
+
+        reopened.insert(b"cat", b"meow").unwrap();
+        reopened.remove(b"horse").unwrap();
+
+        let mut expected = EthTrie::new();
+        expected.insert(b"do", b"verb").unwrap();
+        expected.insert(b"dog", b"puppy").unwrap();
+        expected.insert(b"doge", b"coin").unwrap();
+        expected.insert(b"cat", b"meow").unwrap();
+
+        assert_eq!(reopened.root_hash(), expected.root_hash());
+        assert_eq!(reopened.commit().unwrap(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_commit_only_writes_modified_nodes() {
+        let mut trie = EthTrie::new();
+        for i in 0..200 {
+            let key = format!("key{}", i);
+            trie.insert(key.as_bytes(), &long_value(&key)).unwrap();
+        }
+        trie.commit().unwrap();
+        let stored = trie.db().len();
+
+        trie.insert(b"key7", &long_value("updated")).unwrap();
+        trie.commit().unwrap();
+
+        let written = trie.db().len() - stored;
+        assert!(written > 0);
+        assert!(written <= 6, "expected only the modified path to be written, got {} nodes", written);
+    }
+
+    #[test]
+    fn test_remove_collapses_into_committed_sibling() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"dog", &long_value("puppy")).unwrap();
+        trie.insert(b"horse", &long_value("stallion")).unwrap();
+        let root = trie.commit().unwrap();
+
+        let mut reopened = EthTrie::from_root(root, trie.into_db());
+        reopened.remove(b"horse").unwrap();
+
+        let mut expected = EthTrie::new();
+        expected.insert(b"dog", &long_value("puppy")).unwrap();
+
+        assert_eq!(reopened.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_file_db_backed_trie_round_trip() {
+        use crate::db::FileDb;
+
+        let path = std::env::temp_dir().join(format!("merkle-trie-roundtrip-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut expected = EthTrie::new();
+        for batch in 0..3 {
+            let db = FileDb::open(&path).unwrap();
+            let mut trie = EthTrie::from_root(db.root(), db);
+            for i in 0..30 {
+                let key = format!("key{}-{}", batch, i);
+                trie.insert(key.as_bytes(), &long_value(&key)).unwrap();
+                expected.insert(key.as_bytes(), &long_value(&key)).unwrap();
+            }
+            let root = trie.commit().unwrap();
+            trie.db_mut().set_root(root).unwrap();
+        }
+
+        let db = FileDb::open(&path).unwrap();
+        assert_eq!(db.root(), expected.root_hash());
+        let trie = EthTrie::from_root(db.root(), db);
+        assert_eq!(trie.get(b"key1-17").unwrap(), Some(long_value("key1-17")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn exclusion_trie() -> EthTrie {
+        let mut trie = EthTrie::new();
+        trie.insert(b"do", &long_value("verb")).unwrap();
+        trie.insert(b"dog", &long_value("puppy")).unwrap();
+        trie.insert(b"doge", &long_value("coin")).unwrap();
+        trie.insert(b"horse", &long_value("stallion")).unwrap();
+        trie
+    }
+
+    fn assert_proven_absent(trie: &EthTrie, key: &[u8]) {
+        let root_hash = trie.root_hash();
+        let proof = trie.get_proof(key).unwrap();
+        assert_eq!(
+            EthTrie::verify_proof(&root_hash, key, &proof),
+            Ok(None),
+            "expected an exclusion proof for {:?}",
+            String::from_utf8_lossy(key)
+        );
+    }
+
+    #[test]
+    fn test_exclusion_proof_leaf_with_different_key() {
+        let trie = exclusion_trie();
+        assert_proven_absent(&trie, b"dogs");
+        assert_proven_absent(&trie, b"horsa");
+    }
+
+    #[test]
+    fn test_exclusion_proof_extension_prefix_mismatch() {
+        let trie = exclusion_trie();
+        // every key shares the extension for the first nibble of 'd'/'h'
+        // (0x6); 'x' (0x78) diverges inside it.
+        assert!(matches!(*trie.root, Node::Extension { .. }));
+        assert_proven_absent(&trie, b"x");
+        // 'd' runs out of nibbles halfway through the 'o' extension.
+        assert_proven_absent(&trie, b"d");
+    }
+
+    #[test]
+    fn test_exclusion_proof_empty_branch_slot() {
+        let trie = exclusion_trie();
+        assert_proven_absent(&trie, b"dox");
+        assert_proven_absent(&trie, b"a");
+    }
+
+    #[test]
+    fn test_exclusion_proof_branch_without_value() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"a\x10", &long_value("one")).unwrap();
+        trie.insert(b"a\x20", &long_value("two")).unwrap();
+
+        // 'a' ends exactly at the branch splitting 0x1_ and 0x2_, which holds
+        // no value of its own.
+        assert_proven_absent(&trie, b"a");
+    }
+
+    #[test]
+    fn test_exclusion_proof_empty_trie() {
+        let trie = EthTrie::new();
+        assert_proven_absent(&trie, b"anything");
+    }
+
+    #[test]
+    fn test_exclusion_proof_with_missing_node_is_invalid() {
+        let trie = exclusion_trie();
+        let root_hash = trie.root_hash();
+        let proof = trie.get_proof(b"dox").unwrap();
+
+        let result = EthTrie::verify_proof(&root_hash, b"dox", &proof[..1]);
+        assert!(matches!(result, Err(ProofError::MissingNode { depth, .. }) if depth > 0));
+    }
+
+    #[test]
+    fn test_verify_proof_rejects_undecodable_node() {
+        let garbage = vec![0xc2, 0x01, 0x02];
+        let root_hash = keccak256(&garbage);
+
+        let result = EthTrie::verify_proof(&root_hash, b"dog", &[vec![0x80], garbage]);
+        assert!(
+            matches!(result, Err(ProofError::InvalidNode { index: 1, depth: 0, .. })),
+            "expected the second item to be reported, got {:?}",
+            result
+        );
+    }
+
+    fn dirty_nodes(node: &Node) -> usize {
+        let own = usize::from(!node.is_cached());
+        match node {
+            Node::Extension { next, .. } => own + dirty_nodes(next),
+            Node::Branch { children, .. } => own + children.iter().map(|child| dirty_nodes(child)).sum::<usize>(),
+            _ => own,
+        }
+    }
+
+    fn total_nodes(node: &Node) -> usize {
+        match node {
+            Node::Null => 0,
+            Node::Extension { next, .. } => 1 + total_nodes(next),
+            Node::Branch { children, .. } => 1 + children.iter().map(|child| total_nodes(child)).sum::<usize>(),
+            _ => 1,
+        }
+    }
+
+    #[test]
+    fn test_root_hash_only_rehashes_modified_path() {
+        let mut trie = EthTrie::new();
+        for i in 0..500 {
+            trie.insert(format!("key_{}", i).as_bytes(), &long_value("v")).unwrap();
+        }
+        trie.root_hash();
+        assert_eq!(dirty_nodes(&trie.root), 0);
+        assert!(total_nodes(&trie.root) > 500);
+
+        trie.insert(b"key_250", &long_value("updated")).unwrap();
+        let dirty = dirty_nodes(&trie.root);
+        assert!(dirty > 0 && dirty <= 8, "insert left {} dirty nodes", dirty);
+        trie.root_hash();
+        assert_eq!(dirty_nodes(&trie.root), 0);
+
+        trie.remove(b"key_7").unwrap();
+        let dirty = dirty_nodes(&trie.root);
+        assert!(dirty > 0 && dirty <= 8, "remove left {} dirty nodes", dirty);
+    }
+
+    #[test]
+    fn test_cached_root_hash_matches_fresh_trie() {
+        let mut trie = EthTrie::new();
+        for i in 0..200 {
+            trie.insert(format!("key_{}", i).as_bytes(), format!("value_{}", i).as_bytes()).unwrap();
+            trie.root_hash();
+        }
+        for i in (0..200).step_by(3) {
+            trie.remove(format!("key_{}", i).as_bytes()).unwrap();
+            trie.root_hash();
+        }
+        for i in (0..200).step_by(7) {
+            trie.insert(format!("key_{}", i).as_bytes(), b"rewritten").unwrap();
+            trie.root_hash();
+        }
+
+        let mut expected = EthTrie::new();
+        for i in 0..200 {
+            let value = if i % 7 == 0 {
+                b"rewritten".to_vec()
+            } else if i % 3 == 0 {
+                continue;
+            } else {
+                format!("value_{}", i).into_bytes()
+            };
+            expected.insert(format!("key_{}", i).as_bytes(), &value).unwrap();
+        }
+
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_resolved_nodes_keep_their_hash() {
+        let mut trie = EthTrie::new();
+        for i in 0..100 {
+            trie.insert(format!("key_{}", i).as_bytes(), &long_value("v")).unwrap();
+        }
+        let root = trie.commit().unwrap();
+
+        let mut trie = EthTrie::from_root(root, trie.into_db());
+        trie.insert(b"key_42", &long_value("updated")).unwrap();
+        assert!(dirty_nodes(&trie.root) <= 8);
+
+        let mut expected = EthTrie::new();
+        for i in 0..100 {
+            let value = if i == 42 { long_value("updated") } else { long_value("v") };
+            expected.insert(format!("key_{}", i).as_bytes(), &value).unwrap();
+        }
+        assert_eq!(trie.root_hash(), expected.root_hash());
+    }
+
+    fn multiproof_trie() -> EthTrie {
+        let mut trie = EthTrie::new();
+        for i in 0..500 {
+            trie.insert(&keccak256(&[i as u8, (i >> 8) as u8]), &long_value(&i.to_string())).unwrap();
+        }
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie
+    }
+
+    #[test]
+    fn test_multiproof_proves_every_key() {
+        let mut trie = multiproof_trie();
+        let mut keys: Vec<Vec<u8>> = (0..500).step_by(3).map(|i: u32| keccak256(&[i as u8, (i >> 8) as u8]).to_vec()).collect();
+        keys.extend([b"do".to_vec(), b"dog".to_vec(), b"doge".to_vec(), b"d".to_vec(), vec![0xff; 32]]);
+
+        // the same proof from an in-memory and a committed trie.
+        let proof = trie.get_multiproof(&keys).unwrap();
+        let root = trie.commit().unwrap();
+        assert_eq!(trie.get_multiproof(&keys).unwrap(), proof);
+
+        let values = EthTrie::verify_multiproof(&root, &keys, &proof).unwrap();
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(value, &trie.get(key).unwrap(), "key {}", hex::encode(key));
+        }
+        assert_eq!(values[values.len() - 3], None);
+        assert_eq!(values[values.len() - 4], Some(b"puppy".to_vec()));
+    }
+
+    #[test]
+    fn test_multiproof_deduplicates_shared_nodes() {
+        let trie = multiproof_trie();
+        let keys: Vec<Vec<u8>> = (0..500).map(|i: u32| keccak256(&[i as u8, (i >> 8) as u8]).to_vec()).collect();
+
+        let proof = trie.get_multiproof(&keys).unwrap();
+        let unique: HashSet<&Vec<u8>> = proof.iter().collect();
+        assert_eq!(unique.len(), proof.len());
+        assert_eq!(proof.iter().filter(|item| keccak256(item) == trie.root_hash()).count(), 1);
+
+        let separate: Vec<Vec<Vec<u8>>> = keys.iter().map(|key| trie.get_proof(key).unwrap()).collect();
+        let total: usize = separate.iter().map(Vec::len).sum();
+        assert!(proof.len() * 2 < total, "{} nodes vs {} in separate proofs", proof.len(), total);
+
+        // exactly the nodes the separate proofs reach by hash.
+        let hashed: HashSet<&Vec<u8>> = separate.iter().flatten().filter(|item| item.len() >= 32).collect();
+        assert_eq!(unique, hashed);
+    }
+
+    #[test]
+    fn test_multiproof_empty_trie_and_no_keys() {
+        let trie = EthTrie::new();
+        let proof = trie.get_multiproof([b"a", b"b"]).unwrap();
+        assert!(proof.is_empty());
+        assert_eq!(EthTrie::verify_multiproof(&EMPTY_ROOT, [b"a", b"b"], &proof).unwrap(), vec![None, None]);
+
+        let trie = multiproof_trie();
+        assert!(trie.get_multiproof(Vec::<Vec<u8>>::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_multiproof_small_root() {
+        let mut trie = EthTrie::new();
+        trie.insert(b"a", b"1").unwrap();
+        trie.insert(b"b", b"2").unwrap();
+        let proof = trie.get_multiproof([b"a", b"c"]).unwrap();
+        assert_eq!(proof.len(), 1);
+        assert_eq!(
+            EthTrie::verify_multiproof(&trie.root_hash(), [b"a", b"c"], &proof).unwrap(),
+            vec![Some(b"1".to_vec()), None]
+        );
+    }
+
+    #[test]
+    fn test_multiproof_requires_linked_nodes() {
+        let trie = multiproof_trie();
+        let root = trie.root_hash();
+        let keys = [b"do".to_vec(), b"dog".to_vec()];
+        let proof = trie.get_multiproof(&keys).unwrap();
+        assert!(proof.len() > 1);
+
+        // dropping a node leaves its parent's reference unresolved.
+        let mut missing = proof.clone();
+        missing.pop();
+        assert!(matches!(
+            EthTrie::verify_multiproof(&root, &keys, &missing),
+            Err(ProofError::MissingNode { .. })
+        ));
+
+        // a node that does not hash to its parent's reference is never used.
+        let mut forged = proof.clone();
+        let mut forged_trie = EthTrie::new();
+        forged_trie.insert(b"dog", &long_value("kitten")).unwrap();
+        forged.push(forged_trie.get_proof(b"dog").unwrap().remove(0));
+        assert_eq!(
+            EthTrie::verify_multiproof(&root, &keys, &forged).unwrap(),
+            vec![Some(b"verb".to_vec()), Some(b"puppy".to_vec())]
+        );
+        assert!(EthTrie::verify_multiproof(&forged_trie.root_hash(), &keys, &proof).is_err());
+    }
+
+    type Write = (Vec<u8>, Option<Vec<u8>>);
+
+    fn apply_sequentially(trie: &mut EthTrie, writes: &[Write]) {
+        for (key, value) in writes {
+            match value {
+                Some(value) => trie.insert(key, value).unwrap(),
+                None => {
+                    trie.remove(key).unwrap();
+                }
+            }
+        }
+    }
+
+    /// a mix of updates, new keys, and removals of present and absent keys.
+    fn mixed_writes() -> Vec<Write> {
+        (0..600u32)
+            .map(|i| {
+                let key = keccak256(&(i % 450).to_be_bytes()).to_vec();
+                let value = match i % 5 {
+                    0 | 1 => None,
+                    _ => Some(format!("batch-{}", i).into_bytes()),
+                };
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn batch_base_trie() -> EthTrie {
+        let mut trie = EthTrie::new();
+        for i in 0..300u32 {
+            trie.insert(&keccak256(&i.to_be_bytes()), &long_value(&i.to_string())).unwrap();
+        }
+        trie
+    }
+
+    #[test]
+    fn test_apply_batch_matches_sequential_writes() {
+        let writes = mixed_writes();
+        let mut expected = batch_base_trie();
+        apply_sequentially(&mut expected, &writes);
+
+        let mut trie = batch_base_trie();
+        let root = trie.apply_batch(writes.iter().map(|(key, value)| (key, value.as_ref()))).unwrap();
+        assert_eq!(root, expected.root_hash());
+        assert_eq!(trie.root_hash(), root);
+
+        let entries: Vec<_> = trie.iter().collect::<Result<_, _>>().unwrap();
+        let expected_entries: Vec<_> = expected.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries, expected_entries);
+    }
+
+    #[test]
+    fn test_apply_batch_on_committed_trie() {
+        let writes = mixed_writes();
+        let mut expected = batch_base_trie();
+        apply_sequentially(&mut expected, &writes);
+
+        let mut trie = batch_base_trie();
+        let root = trie.commit().unwrap();
+        let mut trie = EthTrie::from_root(root, trie.into_db());
+        assert_eq!(trie.apply_batch(writes).unwrap(), expected.root_hash());
+        assert_eq!(trie.commit().unwrap(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_apply_batch_builds_from_empty() {
+        let writes: Vec<Write> = [("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion"), ("", "empty key")]
+            .iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), Some(value.as_bytes().to_vec())))
+            .collect();
+        let mut expected = EthTrie::new();
+        apply_sequentially(&mut expected, &writes);
+
+        let mut trie = EthTrie::new();
+        assert_eq!(trie.apply_batch(writes.clone()).unwrap(), expected.root_hash());
+
+        // removing a key whose value sits in a branch, and its neighbours.
+        let removals = [(b"do".to_vec(), None), (b"doge".to_vec(), None), (b"dogs".to_vec(), None::<Vec<u8>>)];
+        apply_sequentially(&mut expected, &removals);
+        assert_eq!(trie.apply_batch(removals).unwrap(), expected.root_hash());
+        assert_eq!(trie.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+    }
+
+    #[test]
+    fn test_apply_batch_last_write_wins() {
+        let mut trie = EthTrie::new();
+        trie.apply_batch([(b"a", Some(b"1")), (b"b", Some(b"2")), (b"a", None), (b"b", Some(b"3"))]).unwrap();
+        assert_eq!(trie.get(b"a").unwrap(), None);
+        assert_eq!(trie.get(b"b").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_apply_batch_removing_everything() {
+        let mut trie = batch_base_trie();
+        let removals = (0..300u32).map(|i| (keccak256(&i.to_be_bytes()), None::<Vec<u8>>));
+        assert_eq!(trie.apply_batch(removals).unwrap(), EMPTY_ROOT);
+    }
+
+    /// runs `apply_at` on the trie's root without hashing it afterwards,
+    /// so tests can see which caches the writes cleared.
+    fn apply_unhashed(trie: &mut EthTrie, keys: &[[u8; 32]]) -> bool {
+        let mut ops: Vec<BatchOp> = keys.iter().map(|key| (Nibbles::from_raw(key, false).as_slice().to_vec(), None)).collect();
+        ops.sort();
+        let mut root = std::mem::take(&mut *trie.root);
+        let changed = trie.apply_at(&mut root, &mut ops, 0, &mut None).unwrap();
+        *trie.root = root;
+        changed
+    }
+
+    #[test]
+    fn test_apply_batch_leaves_unchanged_subtrees_hashed() {
+        let mut trie = batch_base_trie();
+        trie.root_hash();
+        let absent: Vec<[u8; 32]> = (1000..1200u32).map(|i| keccak256(&i.to_be_bytes())).collect();
+        assert!(!apply_unhashed(&mut trie, &absent));
+        assert!(trie.root.is_cached());
+
+        let removed = keccak256(&0u32.to_be_bytes());
+        assert!(apply_unhashed(&mut trie, &[removed, absent[0], absent[1]]));
+        assert!(!trie.root.is_cached());
+        let Node::Branch { children, .. } = &*trie.root else { panic!("expected a branch root") };
+        let touched = (removed[0] >> 4) as usize;
+        for (i, child) in children.iter().enumerate() {
+            assert_eq!(child.is_cached(), i != touched, "child {}", i);
+        }
+    }
+
+    #[test]
+    fn test_apply_batch_of_absent_keys_loads_only_the_root() {
+        use crate::db::CountingDb;
+
+        // every key shares the nibbles of "key" and the high nibble of the
+        // digit, so the root is an extension whose last nibble "key@"
+        // leaves, and the extension's child is hashed.
+        let mut trie = EthTrie::new();
+        for i in 0..200u32 {
+            trie.insert(format!("key{}", i).as_bytes(), &long_value(&i.to_string())).unwrap();
+        }
+        let root = trie.commit().unwrap();
+        let mut trie = EthTrie::from_root(root, CountingDb::new(trie.into_db()));
+        let absent = [(b"key@1", None::<Vec<u8>>), (b"key@2", None)];
+        assert_eq!(trie.apply_batch(absent).unwrap(), root);
+        assert_eq!(trie.db().reads.get(), 1);
+
+        let mut trie = EthTrie::new();
+        trie.insert(b"only", &long_value("only")).unwrap();
+        let root = trie.commit().unwrap();
+        let mut trie = EthTrie::from_root(root, CountingDb::new(trie.into_db()));
+        assert_eq!(trie.apply_batch([(b"a", None::<Vec<u8>>), (b"b", None)]).unwrap(), root);
+        assert_eq!(trie.db().reads.get(), 1);
+    }
+
+    #[test]
+    fn test_apply_batch_journals_only_changed_keys() {
+        let mut trie = batch_base_trie();
+        let root = trie.root_hash();
+        let checkpoint = trie.checkpoint();
+        let writes = [
+            (keccak256(&1u32.to_be_bytes()), Some(b"updated".to_vec())),
+            (keccak256(&2u32.to_be_bytes()), None),
+            (keccak256(&900u32.to_be_bytes()), None),
+            (keccak256(&901u32.to_be_bytes()), Some(b"new".to_vec())),
+        ];
+        trie.apply_batch(writes).unwrap();
+        assert_eq!(trie.journal().len(), 3);
+        trie.revert_to(checkpoint).unwrap();
+        assert_eq!(trie.root_hash(), root);
+    }
+
+    #[test]
+    fn test_apply_batch_is_reverted_by_checkpoint() {
+        let mut trie = batch_base_trie();
+        let root = trie.root_hash();
+        let checkpoint = trie.checkpoint();
+        trie.apply_batch(mixed_writes()).unwrap();
+        assert_ne!(trie.root_hash(), root);
+        trie.revert_to(checkpoint).unwrap();
+        assert_eq!(trie.root_hash(), root);
+    }
+
+    #[cfg(feature = "parallel")]
+    fn sequential_root(trie: &EthTrie) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(trie.root.reference());
+        hash
+    }
+
+    #[cfg(feature = "parallel")]
+    fn parallel_trie(count: u32) -> EthTrie {
+        let mut trie = EthTrie::new();
+        for i in 0..count {
+            let key = keccak256(&i.to_be_bytes());
+            trie.insert(&key, &key[..8]).unwrap();
+        }
+        trie
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_root_matches_sequential() {
+        for count in [0, 1, 2, 17, 300, 5000] {
+            let expected = if count == 0 { EMPTY_ROOT } else { sequential_root(&parallel_trie(count)) };
+            assert_eq!(parallel_trie(count).root_hash(), expected, "{} keys", count);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_hash_fills_top_children() {
+        let trie = parallel_trie(20_000);
+        trie.root.hash_in_parallel(PARALLEL_LEVELS);
+        let Node::Branch { children, .. } = &*trie.root else { panic!("expected a branch root") };
+        assert!(!trie.root.is_cached());
+        assert!(children.iter().all(|child| child.is_cached()));
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_hash_leaves_small_tries_sequential() {
+        let trie = parallel_trie(300);
+        assert!(trie.root.count_dirty(usize::MAX) < 16 * 256);
+        trie.root.hash_in_parallel(PARALLEL_LEVELS);
+        let Node::Branch { children, .. } = &*trie.root else { panic!("expected a branch root") };
+        assert!(children.iter().all(|child| !child.is_cached()));
+        assert_eq!(trie.root_hash(), sequential_root(&parallel_trie(300)));
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_count_dirty_stops_at_limit() {
+        let mut trie = parallel_trie(1000);
+        let all = trie.root.count_dirty(usize::MAX);
+        assert!(all > 1000);
+        assert_eq!(trie.root.count_dirty(50), 50);
+        trie.root_hash();
+        assert_eq!(trie.root.count_dirty(usize::MAX), 0);
+        trie.insert(&keccak256(&7u32.to_be_bytes()), b"updated").unwrap();
+        let path = trie.root.count_dirty(usize::MAX);
+        assert!((2..=6).contains(&path), "{} dirty nodes", path);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parallel_rehash_after_updates() {
+        let mut trie = parallel_trie(3000);
+        trie.root_hash();
+        for i in (0..3000u32).step_by(7) {
+            trie.insert(&keccak256(&i.to_be_bytes()), b"updated").unwrap();
+        }
+        trie.remove(&keccak256(&5u32.to_be_bytes())).unwrap();
+
+        let mut expected = parallel_trie(3000);
+        for i in (0..3000u32).step_by(7) {
+            expected.insert(&keccak256(&i.to_be_bytes()), b"updated").unwrap();
+        }
+        expected.remove(&keccak256(&5u32.to_be_bytes())).unwrap();
+        assert_eq!(trie.root_hash(), sequential_root(&expected));
+        assert_eq!(trie.commit().unwrap(), sequential_root(&expected));
     }
 }
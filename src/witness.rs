@@ -0,0 +1,197 @@
+use crate::db::MemoryDb;
+use crate::error::TrieError;
+use crate::node::keccak256;
+use crate::trie::EthTrie;
+
+/// a trie known only through a witness recorded by
+/// `EthTrie::start_witness`, for validating a block without the state.
+///
+/// it supports the same operations as `EthTrie`. any of them that needs a
+/// node the witness does not contain fails with `TrieError::MissingNode`
+/// naming that node.
+pub struct PartialTrie {
+    trie: EthTrie<MemoryDb>,
+}
+
+impl PartialTrie {
+    /// the trie with root `root`, resolving nodes from `nodes` only. nodes
+    /// that nothing references are ignored.
+    pub fn from_witness<I>(root: [u8; 32], nodes: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut db = MemoryDb::new();
+        for node in nodes {
+            let node = node.as_ref();
+            db.insert(keccak256(node), node.to_vec());
+        }
+        PartialTrie {
+            trie: EthTrie::from_root(root, db),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.trie.get(key)
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError> {
+        self.trie.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.trie.remove(key)
+    }
+
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.trie.root_hash()
+    }
+
+    pub fn into_trie(self) -> EthTrie<MemoryDb> {
+        self.trie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trie::{hashed_key, hashed_trie};
+
+    enum Op {
+        Get(Vec<u8>),
+        Insert(Vec<u8>, Vec<u8>),
+        Remove(Vec<u8>),
+    }
+
+    fn committed_trie() -> (EthTrie, [u8; 32]) {
+        let mut trie = hashed_trie(1000);
+        let root = trie.commit().unwrap();
+        let db = trie.into_db();
+        (EthTrie::from_root(root, db), root)
+    }
+
+    fn ops() -> Vec<Op> {
+        vec![
+            Op::Get(hashed_key(3)),
+            Op::Get(hashed_key(5000)),
+            Op::Insert(hashed_key(10), b"updated".to_vec()),
+            Op::Insert(hashed_key(2000), b"new".to_vec()),
+            Op::Remove(hashed_key(20)),
+            Op::Remove(hashed_key(3000)),
+            Op::Get(hashed_key(10)),
+            Op::Insert(b"short".to_vec(), b"key".to_vec()),
+        ]
+    }
+
+    /// the operations a witness is recorded for, on either kind of trie.
+    trait Replay {
+        fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError>;
+        fn write(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError>;
+        fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError>;
+
+        /// runs `ops` and returns the result of every get and remove.
+        fn replay(&mut self, ops: &[Op]) -> Result<Vec<Option<Vec<u8>>>, TrieError> {
+            let mut results = Vec::new();
+            for op in ops {
+                match op {
+                    Op::Get(key) => results.push(self.read(key)?),
+                    Op::Insert(key, value) => self.write(key, value)?,
+                    Op::Remove(key) => results.push(self.delete(key)?),
+                }
+            }
+            Ok(results)
+        }
+    }
+
+    impl Replay for EthTrie {
+        fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+            self.get(key)
+        }
+
+        fn write(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError> {
+            self.insert(key, value)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+            self.remove(key)
+        }
+    }
+
+    impl Replay for PartialTrie {
+        fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+            self.get(key)
+        }
+
+        fn write(&mut self, key: &[u8], value: &[u8]) -> Result<(), TrieError> {
+            self.insert(key, value)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+            self.remove(key)
+        }
+    }
+
+    #[test]
+    fn test_replay_from_witness() {
+        let (mut trie, root) = committed_trie();
+        let ops = ops();
+
+        trie.start_witness();
+        let expected = trie.replay(&ops).unwrap();
+        let witness = trie.take_witness();
+
+        let mut partial = PartialTrie::from_witness(root, &witness);
+        assert_eq!(partial.replay(&ops).unwrap(), expected);
+        assert_eq!(partial.root_hash(), trie.root_hash());
+        assert_ne!(partial.root_hash(), root);
+    }
+
+    #[test]
+    fn test_witness_holds_only_touched_nodes() {
+        let (mut trie, _) = committed_trie();
+        let stored = trie.db().len();
+
+        trie.start_witness();
+        trie.get(&hashed_key(7)).unwrap();
+        let witness = trie.take_witness();
+        // the root, the branches down to the leaf and the leaf itself.
+        assert!(!witness.is_empty() && witness.len() <= 5, "{} nodes", witness.len());
+        assert!(witness.len() < stored);
+
+        // nothing is recorded once the witness is taken.
+        trie.get(&hashed_key(8)).unwrap();
+        assert!(trie.take_witness().is_empty());
+    }
+
+    #[test]
+    fn test_incomplete_witness_reports_missing_node() {
+        let (mut trie, root) = committed_trie();
+        let ops = ops();
+        trie.start_witness();
+        trie.replay(&ops).unwrap();
+        let witness = trie.take_witness();
+
+        for dropped in 0..witness.len() {
+            let mut nodes = witness.clone();
+            let missing = keccak256(&nodes.remove(dropped));
+            let mut partial = PartialTrie::from_witness(root, &nodes);
+            match partial.replay(&ops) {
+                Err(TrieError::MissingNode(hash)) => assert_eq!(hash, missing),
+                other => panic!("expected missing node, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_witness_of_uncommitted_trie() {
+        let mut trie = hashed_trie(100);
+        let root = trie.root_hash();
+        let ops = ops();
+
+        trie.start_witness();
+        let expected = trie.replay(&ops).unwrap();
+        let mut partial = PartialTrie::from_witness(root, trie.take_witness());
+        assert_eq!(partial.replay(&ops).unwrap(), expected);
+        assert_eq!(partial.root_hash(), trie.root_hash());
+    }
+}
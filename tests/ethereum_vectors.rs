@@ -0,0 +1,133 @@
+//! runs the ethereum `TrieTests` fixtures vendored under
+//! `tests/fixtures/TrieTests`.
+
+use std::fs;
+use std::ops::Bound;
+use std::path::PathBuf;
+
+use merkle_trie_rs::secure::SecureTrie;
+use merkle_trie_rs::trie::EthTrie;
+use serde_json::Value;
+
+fn load(name: &str) -> serde_json::Map<String, Value> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/TrieTests").join(name);
+    let text = fs::read_to_string(&path).unwrap_or_else(|err| panic!("reading {}: {}", path.display(), err));
+    match serde_json::from_str(&text).unwrap() {
+        Value::Object(cases) => cases,
+        other => panic!("{}: expected an object of test cases, got {}", name, other),
+    }
+}
+
+/// fixture strings are hex when they start with `0x` and raw bytes otherwise.
+fn bytes(value: &str) -> Vec<u8> {
+    match value.strip_prefix("0x") {
+        Some(hex) => hex::decode(hex).unwrap_or_else(|err| panic!("bad hex {:?}: {}", value, err)),
+        None => value.as_bytes().to_vec(),
+    }
+}
+
+/// the `(key, value)` writes of a case, in order. `in` is either a list of
+/// pairs (order matters) or an object (any order gives the same root). a
+/// null or empty value deletes the key.
+fn writes(case: &Value) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+    let value = |v: &Value| match v {
+        Value::Null => None,
+        Value::String(s) if s.is_empty() => None,
+        Value::String(s) => Some(bytes(s)),
+        other => panic!("unexpected value {}", other),
+    };
+
+    match &case["in"] {
+        Value::Array(pairs) => pairs
+            .iter()
+            .map(|pair| (bytes(pair[0].as_str().unwrap()), value(&pair[1])))
+            .collect(),
+        Value::Object(entries) => entries.iter().map(|(key, v)| (bytes(key), value(v))).collect(),
+        other => panic!("unexpected `in` {}", other),
+    }
+}
+
+fn expected_root(case: &Value) -> String {
+    case["root"].as_str().unwrap().trim_start_matches("0x").to_string()
+}
+
+fn plain_root(case: &Value) -> String {
+    let mut trie = EthTrie::new();
+    for (key, value) in writes(case) {
+        match value {
+            Some(value) => trie.insert(&key, &value).unwrap(),
+            None => {
+                trie.remove(&key).unwrap();
+            }
+        }
+    }
+    hex::encode(trie.root_hash())
+}
+
+fn secure_root(case: &Value) -> String {
+    let mut trie = SecureTrie::new();
+    for (key, value) in writes(case) {
+        match value {
+            Some(value) => trie.insert(&key, &value).unwrap(),
+            None => {
+                trie.remove(&key).unwrap();
+            }
+        }
+    }
+    hex::encode(trie.root_hash())
+}
+
+fn check_roots(file: &str, root_of: fn(&Value) -> String) {
+    let cases = load(file);
+    assert!(!cases.is_empty(), "{} has no cases", file);
+
+    let failures: Vec<String> = cases
+        .iter()
+        .filter_map(|(name, case)| {
+            let (got, expected) = (root_of(case), expected_root(case));
+            (got != expected).then(|| format!("{}: got {}, expected {}", name, got, expected))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{} failures:\n{}", file, failures.join("\n"));
+}
+
+#[test]
+fn trietest() {
+    check_roots("trietest.json", plain_root);
+}
+
+#[test]
+fn trieanyorder() {
+    check_roots("trieanyorder.json", plain_root);
+}
+
+#[test]
+fn trieanyorder_secure_trie() {
+    check_roots("trieanyorder_secureTrie.json", secure_root);
+}
+
+#[test]
+fn trietestnextprev() {
+    for (name, case) in load("trietestnextprev.json") {
+        let mut trie = EthTrie::new();
+        for key in case["in"].as_array().unwrap() {
+            let key = bytes(key.as_str().unwrap());
+            trie.insert(&key, &key).unwrap();
+        }
+
+        for test in case["tests"].as_array().unwrap() {
+            let [key, prev, next] = [0, 1, 2].map(|i| bytes(test[i].as_str().unwrap()));
+
+            let found_prev = trie.range(..key.as_slice()).last().transpose().unwrap();
+            let found_next = trie
+                .range::<&[u8], _>((Bound::Excluded(key.as_slice()), Bound::Unbounded))
+                .next()
+                .transpose()
+                .unwrap();
+
+            let found_prev = found_prev.map(|(key, _)| key).unwrap_or_default();
+            let found_next = found_next.map(|(key, _)| key).unwrap_or_default();
+            assert_eq!((found_prev, found_next), (prev, next), "{}: neighbours of {:?}", name, test[0]);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+#!/bin/sh
+# downloads the upstream TrieTests files read by tests/ethereum_vectors.rs,
+# unchanged, over the copies in this directory.
+set -eu
+
+ref="${1:-develop}"
+base="https://raw.githubusercontent.com/ethereum/tests/$ref/TrieTests"
+dir="$(dirname "$0")"
+
+for file in trietest.json trieanyorder.json trieanyorder_secureTrie.json trietestnextprev.json; do
+    curl -fsSL "$base/$file" -o "$dir/$file"
+done